@@ -1,28 +1,29 @@
-use crate::response_content::get_content;
 use crate::response_content::Mark;
+use crate::response_content::{code_blocks, extract_code, extract_code_blocks, marks, split_code};
 use crate::scenario::Renderer;
-use crate::scenario::StateTrans;
 use crate::scenario::Workflow;
 use crate::scenario::{find_start_items, Prompt};
-use crate::scenario::{get_item, Input};
-use crate::scenario::{parse_scenario, Item};
+use crate::scenario::{get_item, Input, TestCase};
+use crate::scenario::{parse_scenario, validate, Diagnostic, Item};
+use crate::scenario::{Guard, StateTrans, Target};
 use log::warn;
-use openai_api::ask;
-use openai_api::{AiService, AssistantName, CClient};
+use openai_api::{ask_all, ask_stream, Answer, Answers, Attachment, Cancel, StreamEvent};
+use openai_api::{AssistantName, Backends, Session};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use tools::ToolCall;
 
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::{fs, io};
 
 use regex::Regex;
-use std::process::Output;
+use std::process::ExitCode;
 
-use iced::widget::text_editor::Action;
+use iced::widget::text_editor::{Action, Edit, Motion};
 use iced::widget::{
     self, checkbox, column, horizontal_space, row, text_editor, Button, Column, Text,
 };
@@ -36,18 +37,34 @@ use log::{debug, error, info};
 
 use tokio::sync::Mutex;
 
-use openai_api::{connect, Context, OpenAIApiError, OpenAi};
+use openai_api::{connect, service_keys, Context, OpenAIApiError, OpenAi};
 
-use crate::compile::compile;
+use crate::compile::{default_toolchains, Build, Toolchain, Toolchains};
+use crate::execute::Execution;
+use crate::graph::{GraphFormat, Trace};
+use crate::replay::RecordBackend;
+use crate::sessions::{read_session, write_session, Counters, SavedSession};
+use crate::testing::{run_tests, TestReport};
 
-use handlebars::Handlebars;
+use handlebars::{Handlebars, RenderErrorReason};
+use helpers::register_helpers;
+use serde_json::json;
 
 //use thiserror::Error;
 mod compile;
 mod config;
+mod execute;
+mod graph;
+mod headless;
+mod helpers;
 mod openai_api;
+mod replay;
 mod response_content;
 mod scenario;
+mod schema;
+mod sessions;
+mod testing;
+mod tools;
 
 #[derive(Clone, Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -61,8 +78,24 @@ struct Cli {
     prompt_file: String,
     #[arg(long)]
     workflow_file: Option<String>,
+    /// directory of *.hbs partials, each used as {{> file_stem}} (default: partials next to the workflow file)
+    #[arg(long)]
+    partials_dir: Option<String>,
     #[arg(long)]
     output_dir: String,
+    /// record every exchange to this file (conversation.yaml format, or jsonl) for replay
+    #[arg(long)]
+    record: Option<String>,
+    /// yaml of toolchains keyed by code fence language, added to the defaults
+    #[arg(long)]
+    toolchain_file: Option<String>,
+    /// reattach to the assistants and threads saved in <output_dir>/sessions.yaml
+    #[arg(long)]
+    resume: bool,
+    /// session file to go on with if it exists, written by Save and when run stops
+    /// (default for Save and Open: <output_dir>/session.yaml)
+    #[arg(long)]
+    session: Option<String>,
     #[clap(subcommand)]
     command: Commands,
 }
@@ -70,21 +103,59 @@ struct Cli {
 #[derive(Clone, Debug, Subcommand)]
 enum Commands {
     AskAi {
+        /// regexes splitting answers into blocks, the first group being the language
+        /// (default: the fenced code blocks)
         #[arg(long)]
         markers: Option<Vec<String>>,
+        /// items asked before the workflow stops going on by itself, so that
+        /// a Test or a Branch going round does not ask forever
+        #[arg(long, default_value_t = 100)]
+        max_steps: usize,
+    },
+    /// run the workflow without GUI and write conversation.yaml to output_dir
+    Run {
+        /// regexes splitting answers into blocks, the first group being the language
+        /// (default: the fenced code blocks)
+        #[arg(long)]
+        markers: Option<Vec<String>>,
+        /// directory containing <name>.<tag>.txt used as input of Wait states (default: stdin)
+        #[arg(long)]
+        wait_input_dir: Option<String>,
+        #[arg(long, default_value_t = 100)]
+        max_steps: usize,
+    },
+    /// delete the assistants and threads saved in <output_dir>/sessions.yaml
+    Cleanup,
+    /// check the prompts, the workflow and its templates and report every problem
+    Validate,
+    /// print the workflow as a diagram
+    Graph {
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+        /// conversation.yaml whose path through the workflow is highlighted
+        #[arg(long)]
+        conversation: Option<String>,
     },
 }
 
 impl Default for Commands {
     fn default() -> Self {
-        Commands::AskAi { markers: None }
+        Commands::AskAi {
+            markers: None,
+            max_steps: 100,
+        }
     }
 }
 
 impl Cli {
     fn get_markers(&self) -> Result<Vec<Regex>, regex::Error> {
         let res = match &self.command {
-            Commands::AskAi { markers: Some(m) } => {
+            Commands::AskAi {
+                markers: Some(m), ..
+            }
+            | Commands::Run {
+                markers: Some(m), ..
+            } => {
                 let v: Result<Vec<_>, _> = m.iter().map(|s| Regex::new(s)).collect();
                 v
             }
@@ -92,6 +163,20 @@ impl Cli {
         };
         res
     }
+
+    fn max_steps(&self) -> usize {
+        match &self.command {
+            Commands::AskAi { max_steps, .. } | Commands::Run { max_steps, .. } => *max_steps,
+            _ => usize::MAX,
+        }
+    }
+
+    fn session_path(&self) -> PathBuf {
+        match &self.session {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(&self.output_dir).join("session.yaml"),
+        }
+    }
 }
 
 impl Default for Cli {
@@ -101,44 +186,232 @@ impl Default for Cli {
             config_key: "openai".to_string(),
             prompt_file: "prompt.txt".to_string(),
             workflow_file: None,
+            partials_dir: None,
             output_dir: "output".to_string(),
+            record: None,
+            toolchain_file: None,
+            resume: false,
+            session: None,
             command: Commands::default(),
         }
     }
 }
 
-pub fn main() -> Result<(), AssistantError> {
+pub fn main() -> ExitCode {
     env_logger::init();
     let args = Cli::parse();
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{:?}", &e);
+            eprintln!("error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(args: Cli) -> Result<(), AssistantError> {
     debug!("args:{:?}", args);
     let config_content = fs::read_to_string(&args.config_file)?;
-    let config: OpenAi = config::read_config(Some(&args.config_key), &config_content)?;
     let prompt_content = fs::read_to_string(&args.prompt_file)?;
-    let prompt_hash: Box<HashMap<String, Box<Prompt>>> =
+    let mut prompt_hash: Box<HashMap<String, Box<Prompt>>> =
         config::read_config(None, &prompt_content)?;
-    let _markers = args.get_markers()?;
-    let wf = if let Some(ref file) = &args.workflow_file {
+    let mut toolchains = default_toolchains();
+    if let Some(file) = &args.toolchain_file {
+        let added: Toolchains = config::read_config(None, &fs::read_to_string(file)?)?;
+        toolchains.extend(added);
+    }
+    tools::attach_toolchains(&mut prompt_hash, &toolchains);
+    let prompt_dir = Path::new(&args.prompt_file)
+        .parent()
+        .unwrap_or(Path::new(""));
+    testing::load_test_files(&mut prompt_hash, prompt_dir)?;
+    let (wf, workflow_dir) = if let Some(ref file) = &args.workflow_file {
         let workflow_content = fs::read_to_string(file)?;
-        crate::config::read_config(None, &workflow_content)?
+        let mut wf = crate::config::read_config(None, &workflow_content)?;
+        let dir = Path::new(file)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        resolve_templates(&mut wf, &dir);
+        (wf, dir)
     } else {
-        Workflow::default()
+        (Workflow::default(), PathBuf::new())
+    };
+    let partials = match &args.partials_dir {
+        Some(dir) => find_partials(Path::new(dir))?,
+        None if workflow_dir.join("partials").is_dir() => {
+            find_partials(&workflow_dir.join("partials"))?
+        }
+        None => vec![],
+    };
+    match &args.command {
+        Commands::Validate => return validate_scenario(&prompt_hash, &wf, &partials),
+        Commands::Graph {
+            format,
+            conversation,
+        } => return print_graph(&wf, *format, conversation.as_deref()),
+        _ => (),
+    }
+    // checked before anything is set up. Cleanup does not use the workflow.
+    let (prompts, workflow) = match &args.command {
+        Commands::Cleanup => (*prompt_hash, wf),
+        _ => {
+            let (prompts, workflow) =
+                parse_scenario(*prompt_hash, wf).map_err(|e| invalid_workflow(&e))?;
+            let errors = check_templates(&workflow, &partials);
+            if !errors.is_empty() {
+                return Err(invalid_workflow(&errors));
+            }
+            (prompts, workflow)
+        }
+    };
+    let resumed = match (&args.command, &args.session) {
+        (Commands::Cleanup, _) | (_, None) => None,
+        (_, Some(path)) if Path::new(path).exists() => Some(read_session(Path::new(path))?),
+        (_, Some(path)) => {
+            info!("{} not found, starting a new session", path);
+            None
+        }
     };
+    let saved = match (&args.command, args.resume, &resumed) {
+        (Commands::Cleanup, _, _) | (_, true, None) => sessions::read_sessions(&args.output_dir)?,
+        (_, _, Some(session)) => session.sessions.values().cloned().collect(),
+        _ => vec![],
+    };
+    // saved sessions may be on a service no prompt refers to any more
+    let mut keys = service_keys(&args.config_key, &prompts);
+    keys.extend(saved.iter().map(|s| s.service.clone()));
+    keys.retain(|k| !k.is_empty());
+    keys.sort();
+    keys.dedup();
+    let mut backends: Backends = HashMap::new();
+    for key in keys {
+        let config: OpenAi =
+            config::read_config(Some(&key), &config_content).map_err(|e| match e {
+                config::ConfigError::ConversionFailed => {
+                    AssistantError::InvalidConfigKey(key.clone())
+                }
+                otherwise => AssistantError::Config(otherwise),
+            })?;
+        debug!("{}: {:?}", &key, &config);
+        backends.insert(key, config.create_backend()?);
+    }
+    if let Some(record) = &args.record {
+        let talks = Arc::new(std::sync::Mutex::new(vec![]));
+        for backend in backends.values_mut() {
+            *backend = Arc::new(RecordBackend::new(
+                backend.clone(),
+                PathBuf::from(record),
+                talks.clone(),
+            ));
+        }
+    }
+    if let Commands::Cleanup = &args.command {
+        let runtime = tokio::runtime::Runtime::new()?;
+        return runtime.block_on(sessions::cleanup(&backends, &args.output_dir));
+    }
+    let markers = args.get_markers()?;
+    let (name, tag) = find_start_items(&workflow)
+        .into_iter()
+        .next()
+        .ok_or_else(|| invalid_workflow(&[Diagnostic::NoStart]))?;
+    let mut workflow = load_template(workflow)?;
+    if let Some(session) = &resumed {
+        restore_counters(&mut workflow, &session.counters);
+    }
+    let handlebars = template_registry(&workflow, &partials)?;
+    debug!("{:?}", workflow);
+    match &args.command {
+        Commands::AskAi { .. } => {
+            let settings_default = Settings {
+                flags: (
+                    args.clone(),
+                    backends,
+                    prompts,
+                    workflow,
+                    (name, tag),
+                    saved,
+                    toolchains,
+                    handlebars,
+                    resumed,
+                ),
+                ..Default::default()
+            };
 
-    if let Some((prompts, workflow)) = parse_scenario(*prompt_hash, wf) {
-        //parse_scenario() assures validity of unwrap() below
-        let (name, tag) = find_start_items(&workflow).get(0).unwrap().clone();
-        let workflow = load_template(workflow).unwrap();
-        debug!("{:?}", workflow);
-        let client: Option<CClient> = config.create_client();
-        let settings_default = Settings {
-            flags: (args.clone(), config, prompts, workflow, client, (name, tag)),
-            ..Default::default()
-        };
+            Ok(Model::run(settings_default)?)
+        }
+        Commands::Run {
+            wait_input_dir,
+            max_steps,
+            ..
+        } => {
+            let wait_input = wait_input_dir
+                .as_ref()
+                .map(|d| headless::WaitInput::Dir(PathBuf::from(d)))
+                .unwrap_or(headless::WaitInput::Stdin);
+            let runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(headless::run_workflow(
+                backends,
+                args.config_key.clone(),
+                prompts,
+                workflow,
+                handlebars,
+                match &resumed {
+                    Some(session) => session.current.clone(),
+                    None => Some((name, tag)),
+                },
+                resumed.map(|s| s.conversations).unwrap_or_default(),
+                saved,
+                toolchains,
+                markers,
+                args.session.as_ref().map(PathBuf::from),
+                args.output_dir.clone(),
+                wait_input,
+                *max_steps,
+            ))?;
+            Ok(())
+        }
+        Commands::Cleanup | Commands::Validate | Commands::Graph { .. } => Ok(()),
+    }
+}
 
-        Ok(Model::run(settings_default)?)
-    } else {
-        error!("parse_scenario failed");
-        Err(AssistantError::AppAccessError)
+fn invalid_workflow(errors: &[Diagnostic]) -> AssistantError {
+    for e in errors {
+        error!("{}", e);
+    }
+    let messages: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+    AssistantError::Workflow(messages.join("\n"))
+}
+
+fn print_graph<'a>(
+    workflow: &Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    format: GraphFormat,
+    conversation: Option<&str>,
+) -> Result<(), AssistantError> {
+    let trace = match conversation {
+        Some(file) => Trace::new(&replay::read_talks(&PathBuf::from(file))?),
+        None => Trace::default(),
+    };
+    print!("{}", graph::render(workflow, format, &trace));
+    Ok(())
+}
+
+/// Prints every problem of the prompts, the workflow and its templates.
+fn validate_scenario<'a>(
+    prompts: &HashMap<String, Box<Prompt>>,
+    workflow: &Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    partials: &[PathBuf],
+) -> Result<(), AssistantError> {
+    let mut diagnostics = validate(prompts, workflow);
+    diagnostics.extend(check_templates(workflow, partials));
+    for d in &diagnostics {
+        let level = if d.is_error() { "error" } else { "warning" };
+        println!("{}: {}", level, d);
+    }
+    match diagnostics.iter().filter(|d| d.is_error()).count() {
+        0 => Ok(()),
+        n => Err(AssistantError::Workflow(format!("{} error(s)", n))),
     }
 }
 
@@ -154,15 +427,35 @@ enum Message {
         name: String,
         tag: String,
     },
+    AnswerDelta {
+        name: String,
+        tag: String,
+        delta: String,
+    },
     Answered {
-        answer: Result<(String, String, String), (String, OpenAIApiError)>,
+        answer: Result<(String, String, Answer), (String, OpenAIApiError)>,
+    },
+    CancelAi,
+    Compile {
+        name: String,
+        tag: String,
+    },
+    Compiled {
+        name: String,
+        tag: String,
+        lang: String,
+        result: Result<(Build, Option<Execution>, Option<TestReport>), AssistantError>,
+    },
+    FannedOut {
+        join: Target,
+        answers: Answers,
     },
 
     ActionPerformed((AreaIndex, text_editor::Action)),
-    SaveConversation {
-        outut_dir: String,
-    },
+    SaveSession,
+    OpenSession,
     Toggled(String, usize, bool),
+    ShowProcessed(bool),
     FontLoaded(Result<(), font::Error>),
     DoNothing,
 }
@@ -196,22 +489,29 @@ enum AreaIndex {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 enum Content {
     Text(String),
-    Json(String),
+    /// JSON taken from an answer and validated against the schema of its item.
+    Json(serde_json::Value),
     Fsharp(String),
+    /// A block split out of an answer by the markers, in the language of its marker.
+    Code {
+        lang: String,
+        text: String,
+    },
 }
 
 impl Content {
     fn get_text(&self) -> String {
         match self {
             Content::Text(text) => text.clone(),
-            Content::Json(text) => text.clone(),
+            Content::Json(value) => serde_json::to_string_pretty(value).unwrap_or_default(),
             Content::Fsharp(text) => text.clone(),
+            Content::Code { text, .. } => text.clone(),
         }
     }
 }
 
 type Tag = String;
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, strum_macros::IntoStaticStr)]
 enum Talk {
     OriginalInput {
         name: AssistantName,
@@ -233,130 +533,624 @@ enum Talk {
         tag: Tag,
         message: Content,
     },
+    /// Non text content which came with the preceding `FromAi`.
+    Attachment {
+        name: AssistantName,
+        tag: Tag,
+        attachment: Attachment,
+    },
+    /// A tool executed locally at the request of the assistant.
+    ToolCall {
+        name: AssistantName,
+        tag: Tag,
+        call: ToolCall,
+    },
+    /// Code in the answer compiled with the toolchain of `lang`.
+    Build {
+        name: AssistantName,
+        tag: Tag,
+        lang: String,
+        success: bool,
+        output: String,
+    },
+    /// The built program run in the sandbox.
+    Run {
+        name: AssistantName,
+        tag: Tag,
+        execution: Execution,
+    },
+    /// The tests of the input run on the built program.
+    Tests {
+        name: AssistantName,
+        tag: Tag,
+        report: TestReport,
+    },
+    /// The items of a fan-out answered and `(name, tag)` is asked next.
+    Joined {
+        name: AssistantName,
+        tag: Tag,
+        from: Vec<(AssistantName, Tag)>,
+    },
+    /// What the user checked in the list answer of `(name, tag)`.
+    Selected {
+        name: AssistantName,
+        tag: Tag,
+        items: Vec<String>,
+    },
 }
 
 impl Talk {
+    fn name_tag(&self) -> (&AssistantName, &Tag) {
+        match self {
+            Talk::OriginalInput { name, tag, .. }
+            | Talk::ToAi { name, tag, .. }
+            | Talk::FromAi { name, tag, .. }
+            | Talk::ProcessedResponse { name, tag, .. }
+            | Talk::Attachment { name, tag, .. }
+            | Talk::ToolCall { name, tag, .. }
+            | Talk::Build { name, tag, .. }
+            | Talk::Run { name, tag, .. }
+            | Talk::Tests { name, tag, .. }
+            | Talk::Joined { name, tag, .. }
+            | Talk::Selected { name, tag, .. } => (name, tag),
+        }
+    }
+
     fn get_message<'a>(&self) -> Content {
         let n = match self {
             Talk::OriginalInput { message, .. } => message,
             Talk::ToAi { message, .. } => message,
             Talk::FromAi { message, .. } => message,
             Talk::ProcessedResponse { message, .. } => message,
+            Talk::Attachment { attachment, .. } => {
+                return Content::Text(match attachment {
+                    Attachment::ImageFile { file_id } => file_id.clone(),
+                    Attachment::ImageUrl { url } => url.clone(),
+                })
+            }
+            Talk::ToolCall { call, .. } => return Content::Text(call.output.clone()),
+            Talk::Build { output, .. } => return Content::Text(output.clone()),
+            Talk::Run { execution, .. } => return Content::Text(execution.to_text()),
+            Talk::Tests { report, .. } => {
+                return Content::Text(format!("{}\n{}", report.summary(), report.failures()))
+            }
+            Talk::Joined { from, .. } => {
+                let from: Vec<String> = from
+                    .iter()
+                    .map(|(n, t)| format!("({}, {})", n, t))
+                    .collect();
+                return Content::Text(from.join(", "));
+            }
+            Talk::Selected { items, .. } => return Content::Text(items.join("\n")),
         };
         n.clone()
     }
 }
 
-fn filter_talk(
-    conversations: &Vec<Talk>,
-    constructor: impl Fn(String, String, Content) -> Talk,
-) -> Vec<Talk> {
-    let variant = constructor(
-        "".to_string(),
-        "".to_string(),
-        Content::Text("".to_string()),
+/// A `ToolCall` for each executed call, `FromAi` for the text and an
+/// `Attachment` for each non text content.
+fn answer_talks(name: &AssistantName, tag: &Tag, answer: Answer) -> Vec<Talk> {
+    let mut talks: Vec<Talk> = answer
+        .tool_calls
+        .into_iter()
+        .map(|call| Talk::ToolCall {
+            name: name.clone(),
+            tag: tag.clone(),
+            call,
+        })
+        .collect();
+    talks.push(Talk::FromAi {
+        name: name.clone(),
+        tag: tag.clone(),
+        message: Content::Text(answer.text),
+    });
+    talks.extend(
+        answer
+            .attachments
+            .into_iter()
+            .map(|attachment| Talk::Attachment {
+                name: name.clone(),
+                tag: tag.clone(),
+                attachment,
+            }),
     );
+    talks
+}
+
+/// A `ProcessedResponse` for each block of the last answer of `(name, tag)`
+/// which `markers` split out with a language. Without markers, these are the
+/// fenced code blocks of the answer.
+fn processed_talks(
+    conversations: &[Talk],
+    name: &AssistantName,
+    tag: &Tag,
+    markers: &[Regex],
+) -> Vec<Talk> {
+    let answer = last_answer(conversations, name, tag).unwrap_or_default();
+    let marks = if markers.is_empty() {
+        marks(&answer)
+    } else {
+        split_code(&answer, markers)
+    };
+    marks
+        .into_iter()
+        .filter_map(|mark| match mark {
+            Mark::Content {
+                text,
+                lang: Some(lang),
+            } => Some(Talk::ProcessedResponse {
+                name: name.clone(),
+                tag: tag.clone(),
+                message: Content::Code { lang, text },
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The markers of the item if it has any, `default` otherwise.
+fn item_markers<'a>(
+    item: &Item<RenderingContext<'a>, Rendered, Request, Response>,
+    default: &[Regex],
+) -> Vec<Regex> {
+    match &item.markers {
+        // checked by validate
+        Some(markers) => markers.iter().filter_map(|m| Regex::new(m).ok()).collect(),
+        None => default.to_vec(),
+    }
+}
+
+/// The blocks processed from the last answer of `(name, tag)`, if any.
+fn processed_text(conversations: &[Talk], name: &AssistantName, tag: &Tag) -> Option<String> {
+    let mut blocks = vec![];
+    for talk in conversations.iter().rev() {
+        match talk {
+            Talk::ProcessedResponse {
+                name: n,
+                tag: t,
+                message: Content::Code { lang, text },
+            } if (n, t) == (name, tag) => blocks.push(format!("--- {}\n{}", lang, text)),
+            Talk::FromAi {
+                name: n, tag: t, ..
+            } if (n, t) == (name, tag) => break,
+            _ => (),
+        }
+    }
+    blocks.reverse();
+    (!blocks.is_empty()).then(|| blocks.join("\n"))
+}
+
+/// Text of the last answer of `(name, tag)`.
+fn last_answer(conversations: &[Talk], name: &AssistantName, tag: &Tag) -> Option<String> {
+    conversations.iter().rev().find_map(|talk| match talk {
+        Talk::FromAi {
+            name: n,
+            tag: t,
+            message,
+        } if (n, t) == (name, tag) => Some(message.get_text()),
+        _ => None,
+    })
+}
+
+/// Output of the last build of `(name, tag)` if it failed, empty otherwise.
+fn compile_errors(conversations: &[Talk], name: &AssistantName, tag: &Tag) -> String {
     conversations
         .iter()
-        .filter(|&talk| match (talk, &variant) {
-            (Talk::OriginalInput { .. }, Talk::OriginalInput { .. }) => true,
-            (Talk::FromAi { .. }, Talk::FromAi { .. }) => true,
-            (Talk::ToAi { .. }, Talk::ToAi { .. }) => true,
-            (Talk::ProcessedResponse { .. }, Talk::ProcessedResponse { .. }) => true,
-            _ => false,
+        .rev()
+        .find_map(|talk| match talk {
+            Talk::Build {
+                name: n,
+                tag: t,
+                success,
+                output,
+                ..
+            } if (n, t) == (name, tag) => Some(if *success {
+                String::new()
+            } else {
+                output.clone()
+            }),
+            _ => None,
         })
-        .map(|t| t.clone())
-        .collect()
+        .unwrap_or_default()
 }
 
+fn last_report(conversations: &[Talk]) -> Option<&TestReport> {
+    conversations.iter().rev().find_map(|talk| match talk {
+        Talk::Tests { report, .. } => Some(report),
+        _ => None,
+    })
+}
+
+/// The last answer of each item of the last fan-out, headed by the item.
+fn fan_out_answers(conversations: &[Talk]) -> String {
+    let from = conversations.iter().rev().find_map(|talk| match talk {
+        Talk::Joined { from, .. } => Some(from),
+        _ => None,
+    });
+    from.map(|from| {
+        from.iter()
+            .map(|(name, tag)| {
+                let answer = last_answer(conversations, name, tag).unwrap_or_default();
+                format!("--- ({}, {})\n{}", name, tag, answer)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+    .unwrap_or_default()
+}
+
+/// Renders the request of each item of `to` from the conversation as it is,
+/// before any of them is asked, so that all of them get the same input.
+fn fan_out_queries<'a>(
+    handlebars: &Templates,
+    workflow: &Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    prompts: &HashMap<String, Box<Prompt>>,
+    conversations: &mut Vec<Talk>,
+    to: &[Target],
+) -> Result<Vec<(AssistantName, Tag, String)>, AssistantError> {
+    let mut queries = vec![];
+    for Target { name, tag } in to {
+        let item = get_item(workflow, name, tag);
+        let input = prompts.get(name).and_then(|p| {
+            p.inputs
+                .get(tag)
+                .map(|i| (p.instruction.clone(), i.clone()))
+        });
+        let (Some(item), Some((instruction, input))) = (item, input) else {
+            return Err(AssistantError::Workflow(format!(
+                "({}, {}) is not defined",
+                name, tag
+            )));
+        };
+        let rendered =
+            item.request
+                .render((handlebars, conversations, &instruction, &input, (name, tag)))?;
+        queries.push((name.clone(), tag.clone(), rendered));
+    }
+    for (name, tag, query) in &queries {
+        conversations.push(Talk::ToAi {
+            name: name.clone(),
+            tag: tag.clone(),
+            message: Content::Text(query.clone()),
+        });
+    }
+    Ok(queries)
+}
+
+/// The answers of a fan-out in the order of its items, then `Joined`.
+fn joined_talks(answers: Vec<(AssistantName, Tag, Answer)>, join: &Target) -> Vec<Talk> {
+    let from = answers
+        .iter()
+        .map(|(name, tag, _)| (name.clone(), tag.clone()))
+        .collect();
+    let mut talks: Vec<Talk> = answers
+        .into_iter()
+        .flat_map(|(name, tag, answer)| answer_talks(&name, &tag, answer))
+        .collect();
+    talks.push(Talk::Joined {
+        name: join.name.clone(),
+        tag: join.tag.clone(),
+        from,
+    });
+    talks
+}
+
+/// Code in the last answer of `(name, tag)` and the toolchain of its language,
+/// or the language and why it cannot be built.
+fn code_to_build<'t>(
+    toolchains: &'t Toolchains,
+    conversations: &[Talk],
+    name: &AssistantName,
+    tag: &Tag,
+) -> Result<(String, &'t Toolchain, String), (String, String)> {
+    let answer = last_answer(conversations, name, tag);
+    let (lang, code) = answer.as_deref().and_then(extract_code).ok_or_else(|| {
+        (
+            String::new(),
+            format!("no code block in the answer of ({}, {})", name, tag),
+        )
+    })?;
+    match toolchains.get(&lang) {
+        Some(toolchain) => Ok((lang, toolchain, code)),
+        None => {
+            let reason = format!("no toolchain for {}", &lang);
+            Err((lang, reason))
+        }
+    }
+}
+
+fn build_dir(output_dir: &str, name: &AssistantName, tag: &Tag) -> PathBuf {
+    PathBuf::from(output_dir)
+        .join("build")
+        .join(format!("{}.{}", name, tag))
+}
+
+/// A template file, or the template itself written in the workflow, in which
+/// case `path` only names it in errors.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 struct Request {
+    #[serde(default)]
     path: String,
     template: Option<String>,
 }
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 struct Response {
+    #[serde(default)]
     path: String,
     template: Option<String>,
 }
 
-type RenderingContext<'a> = (&'a Handlebars<'a>, &'a Vec<Talk>, &'a String, &'a Input);
-impl<'a> Renderer<RenderingContext<'a>, String> for Request {
-    fn render(&self, talks: RenderingContext) -> String {
-        let (hb, t, _s, i) = talks;
-        let mut data = BTreeMap::new();
-        data.insert(
-            "prefix".to_string(),
-            i.prefix.clone().unwrap_or("".to_string()),
-        );
-        data.insert("text".to_string(), i.text.clone());
-        debug!("text:{:?}", &i.text);
-        let response = filter_talk(&t, |n, t, c| Talk::FromAi {
-            name: n,
-            tag: t,
-            message: c,
+// the last one is the item rendered
+type RenderingContext<'a> = (
+    &'a Templates<'a>,
+    &'a [Talk],
+    &'a String,
+    &'a Input,
+    (&'a AssistantName, &'a Tag),
+);
+type Rendered = Result<String, AssistantError>;
+
+/// What request and response templates can refer to. Answers are the last
+/// ones, `iterations` counts them, and `code_blocks` are those of `last_response`.
+/// `json_answers` and `last_json` hold the JSON of answers validated by a schema.
+/// `selected` lists what the user last checked in a list answer.
+/// `compile_errors` are those of the last build of the item rendered.
+fn template_data(
+    talks: &[Talk],
+    instruction: &str,
+    input: &Input,
+    (name, tag): (&AssistantName, &Tag),
+) -> serde_json::Value {
+    let mut last_response = String::new();
+    let mut responses: BTreeMap<&str, String> = BTreeMap::new();
+    let mut answers: BTreeMap<&str, BTreeMap<&str, String>> = BTreeMap::new();
+    let mut iterations: BTreeMap<&str, BTreeMap<&str, usize>> = BTreeMap::new();
+    let mut last_json = serde_json::Value::Null;
+    let mut json_answers: BTreeMap<&str, BTreeMap<&str, serde_json::Value>> = BTreeMap::new();
+    for talk in talks {
+        if let Talk::ProcessedResponse {
+            name,
+            tag,
+            message: Content::Json(value),
+        } = talk
+        {
+            last_json = value.clone();
+            json_answers
+                .entry(name)
+                .or_default()
+                .insert(tag, value.clone());
+        }
+        if let Talk::FromAi { name, tag, message } = talk {
+            last_response = message.get_text();
+            responses.insert(name, last_response.clone());
+            answers
+                .entry(name)
+                .or_default()
+                .insert(tag, last_response.clone());
+            *iterations.entry(name).or_default().entry(tag).or_default() += 1;
+        }
+    }
+    let history: Vec<serde_json::Value> = talks
+        .iter()
+        .map(|talk| {
+            let (name, tag) = talk.name_tag();
+            let kind: &'static str = talk.into();
+            json!({
+                "kind": kind,
+                "name": name,
+                "tag": tag,
+                "text": talk.get_message().get_text(),
+            })
         })
-        .last()
-        .map(|t| t.get_message().get_text())
-        .unwrap_or("".to_string());
-
-        data.insert("last_response".to_string(), response);
+        .collect();
+    let code_blocks: Vec<serde_json::Value> = extract_code_blocks(&last_response)
+        .into_iter()
+        .map(|(lang, code)| json!({ "lang": lang, "code": code }))
+        .collect();
+    let build = talks.iter().rev().find_map(|talk| match talk {
+        Talk::Build {
+            lang,
+            success,
+            output,
+            ..
+        } => Some(json!({ "lang": lang, "success": success, "output": output })),
+        _ => None,
+    });
+    let report = last_report(talks);
+    let selected = talks.iter().rev().find_map(|talk| match talk {
+        Talk::Selected { items, .. } => Some(items.clone()),
+        _ => None,
+    });
+    json!({
+        "prefix": input.prefix.clone().unwrap_or_default(),
+        "text": &input.text,
+        "instruction": instruction,
+        "last_response": &last_response,
+        "responses": responses,
+        "answers": answers,
+        "iterations": iterations,
+        "last_json": last_json,
+        "json_answers": json_answers,
+        "talks": history,
+        "code_blocks": code_blocks,
+        "build": build,
+        "compile_errors": compile_errors(talks, name, tag),
+        "test_summary": report.map(|r| r.summary()).unwrap_or_default(),
+        "test_failures": report.map(|r| r.failures()).unwrap_or_default(),
+        "fan_out_answers": fan_out_answers(talks),
+        "selected": selected,
+    })
+}
 
+impl<'a> Renderer<RenderingContext<'a>, Rendered> for Request {
+    fn render(&self, talks: RenderingContext) -> Rendered {
+        let (hb, t, s, i, item) = talks;
+        debug!("text:{:?}", &i.text);
         debug!("{:?}", &self.path);
         debug!("{:?}", &self.template);
-        hb.render(&self.path, &data).unwrap_or("failed".to_string())
+        let data = template_data(t, s, i, item);
+        render_template(hb, &self.path, &data)
     }
 }
 
-impl<'a> Renderer<RenderingContext<'a>, String> for Response {
-    fn render(&self, talks: RenderingContext) -> String {
-        let (hb, t, _, _) = talks;
-        let response = filter_talk(&t, |n, t, c| Talk::FromAi {
-            name: n,
-            tag: t,
-            message: c,
-        })
-        .last()
-        .map(|t| t.get_message().get_text())
-        .unwrap_or("".to_string());
-        let mut data = BTreeMap::new();
+impl<'a> Renderer<RenderingContext<'a>, Rendered> for Response {
+    fn render(&self, talks: RenderingContext) -> Rendered {
+        let (hb, t, s, i, item) = talks;
         debug!("{:?}", &self.path);
         debug!("{:?}", &self.template);
+        let data = template_data(t, s, i, item);
+        render_template(hb, &self.path, &data)
+    }
+}
 
-        data.insert("last_response".to_string(), response);
-        hb.render(&self.path, &data).unwrap_or("failed".to_string())
+// whether `name` is under one of the names `template_data` gives. Relative
+// paths inside blocks cannot be told, so they are taken as given.
+fn provided(name: &str, data: &serde_json::Value) -> bool {
+    let head = name.split(['.', '/', '[']).next().unwrap_or_default();
+    head.is_empty() || head == "this" || head.starts_with('@') || data.get(head).is_some()
+}
+
+/// Renders `path` with `data`. A variable under one of the names given to
+/// templates which is not there, such as the answer of an item not asked yet
+/// or `build` before anything is built, is empty and logged as a warning.
+/// Any other name, such as a misspelt one, is an error.
+fn render_template(templates: &Templates, path: &str, data: &serde_json::Value) -> Rendered {
+    match templates.strict.render(path, data) {
+        Ok(text) => Ok(text),
+        Err(e) => match e.reason() {
+            RenderErrorReason::MissingVariable(name)
+                if name.as_deref().is_none_or(|name| provided(name, data)) =>
+            {
+                warn!("{}", render_error(path, &e));
+                templates
+                    .lax
+                    .render(path, data)
+                    .map_err(|e| render_error(path, &e))
+            }
+            _ => Err(render_error(path, &e)),
+        },
     }
 }
 
+/// Where rendering stopped and why, naming the variable which is missing.
+fn render_error(path: &str, e: &handlebars::RenderError) -> AssistantError {
+    let reason = match e.reason() {
+        RenderErrorReason::MissingVariable(Some(name)) => format!("missing variable {}", name),
+        otherwise => otherwise.to_string(),
+    };
+    let reason = match (e.line_no, e.column_no) {
+        (Some(line), Some(column)) => format!("line {}, column {}: {}", line, column, reason),
+        _ => reason,
+    };
+    AssistantError::Render(path.to_string(), reason)
+}
+
+fn syntax_error(path: &str, template: &str) -> Option<Diagnostic> {
+    handlebars::Template::compile(template).err().map(|e| {
+        let reason = match e.pos() {
+            Some((line, column)) => format!("line {}, column {}: {}", line, column, e.reason()),
+            None => e.reason().to_string(),
+        };
+        Diagnostic::TemplateSyntax(path.to_string(), reason)
+    })
+}
+
+/// Missing templates and syntax errors in them and in the partials.
+fn check_templates<'a>(
+    workflow: &Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    partials: &[PathBuf],
+) -> Vec<Diagnostic> {
+    let mut templates: Vec<(&String, &Option<String>)> = workflow
+        .values()
+        .flat_map(|hm| hm.values())
+        .flat_map(|item| {
+            [
+                (&item.request.path, &item.request.template),
+                (&item.response.path, &item.response.template),
+            ]
+        })
+        .collect();
+    templates.sort();
+    templates.dedup();
+    let mut diagnostics: Vec<Diagnostic> = templates
+        .into_iter()
+        .filter_map(|(path, template)| match template {
+            Some(template) => syntax_error(path, template),
+            None => match fs::read_to_string(path) {
+                Err(_) => Some(Diagnostic::TemplateMissing(path.clone())),
+                Ok(template) => syntax_error(path, &template),
+            },
+        })
+        .collect();
+    for partial in partials {
+        let path = partial.display().to_string();
+        match fs::read_to_string(partial) {
+            Err(_) => diagnostics.push(Diagnostic::TemplateMissing(path)),
+            Ok(template) => diagnostics.extend(syntax_error(&path, &template)),
+        }
+    }
+    diagnostics
+}
+
+/// Names the templates written in the workflow after their item and makes
+/// relative paths relative to the directory of the workflow file.
+fn resolve_templates<'a>(
+    workflow: &mut Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    dir: &Path,
+) {
+    for (name, hm) in workflow.iter_mut() {
+        for (tag, item) in hm.iter_mut() {
+            for (path, kind) in [
+                (&mut item.request.path, "request"),
+                (&mut item.response.path, "response"),
+            ] {
+                if path.is_empty() {
+                    *path = format!("({}, {}) {}", name, tag, kind);
+                } else {
+                    *path = dir.join(&path).display().to_string();
+                }
+            }
+        }
+    }
+}
+
+/// `*.hbs` in `dir`, each of which is a partial named after its file.
+fn find_partials(dir: &Path) -> Result<Vec<PathBuf>, AssistantError> {
+    let mut partials = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "hbs") {
+            partials.push(path);
+        }
+    }
+    partials.sort();
+    Ok(partials)
+}
+
 fn load_template<'a>(
-    workflow: Workflow<RenderingContext<'a>, String, Request, Response>,
-) -> Result<Workflow<RenderingContext<'a>, String, Request, Response>, AssistantError> {
-    let mut wf: Workflow<RenderingContext<'a>, String, Request, Response> = HashMap::new();
+    workflow: Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+) -> Result<Workflow<RenderingContext<'a>, Rendered, Request, Response>, AssistantError> {
+    let mut wf: Workflow<RenderingContext<'a>, Rendered, Request, Response> = HashMap::new();
     for (name, hmap) in workflow {
         let mut new_hmap = HashMap::new();
         for (tag, item) in hmap {
-            let mut req_file = File::open(item.request.path.clone())
-                .map_err(|_| AssistantError::FileOpenFailed("req open".to_string()))?;
-            let mut req_template = String::new();
-            req_file
-                .read_to_string(&mut req_template)
-                .map_err(|_| AssistantError::FileOpenFailed("req read".to_string()))?;
-
-            let mut rsp_file = File::open(item.response.path.clone())
-                .map_err(|_| AssistantError::FileOpenFailed("rsp open".to_string()))?;
-            let mut rsp_template = String::new();
-            rsp_file
-                .read_to_string(&mut rsp_template)
-                .map_err(|_| AssistantError::FileOpenFailed("rsp read".to_string()))?;
+            let req_template = match &item.request.template {
+                Some(template) => template.clone(),
+                None => read_template(&item.request.path)?,
+            };
+            let rsp_template = match &item.response.template {
+                Some(template) => template.clone(),
+                None => read_template(&item.response.path)?,
+            };
 
             let new_item = Item {
                 request: Box::new(Request {
                     path: item.request.path.clone(),
-                    template: Some(req_template.clone()),
+                    template: Some(req_template),
                 }),
                 response: Box::new(Response {
                     path: item.response.path.clone(),
-                    template: Some(rsp_template.clone()),
+                    template: Some(rsp_template),
                 }),
                 ..item
             };
@@ -367,23 +1161,71 @@ fn load_template<'a>(
     Ok(wf)
 }
 
-fn register_template<'a>(
-    handlebars: &mut Handlebars,
-    workflow: &Workflow<RenderingContext<'a>, String, Request, Response>,
-) -> () {
-    for (_, hm) in workflow {
-        for (_, item) in hm {
-            let item_cloned = item.clone();
-            item_cloned
-                .request
-                .template
-                .map(|t| handlebars.register_template_string(&item.request.path, &t));
-            item_cloned
-                .response
-                .template
-                .map(|t| handlebars.register_template_string(&item.response.path, &t));
+fn read_template(path: &str) -> Result<String, AssistantError> {
+    let mut file =
+        File::open(path).map_err(|_| AssistantError::TemplateMissing(path.to_string()))?;
+    let mut template = String::new();
+    file.read_to_string(&mut template)
+        .map_err(|e| AssistantError::FileOpenFailed(format!("{}: {}", path, e)))?;
+    Ok(template)
+}
+
+/// The registered templates, twice: the strict registry finds the variables
+/// which are missing, and the lax one renders them as empty.
+#[derive(Clone, Debug)]
+struct Templates<'a> {
+    lax: Handlebars<'a>,
+    strict: Handlebars<'a>,
+}
+
+impl<'a> Templates<'a> {
+    fn new(lax: Handlebars<'a>) -> Templates<'a> {
+        let mut strict = lax.clone();
+        strict.set_strict_mode(true);
+        Templates { lax, strict }
+    }
+}
+
+impl Default for Templates<'_> {
+    fn default() -> Self {
+        Templates::new(Handlebars::new())
+    }
+}
+
+/// Helpers, partials and the templates of the loaded workflow, registered
+/// under their path. See `render_template` for missing variables.
+fn template_registry<'a>(
+    workflow: &Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    partials: &[PathBuf],
+) -> Result<Templates<'static>, AssistantError> {
+    let mut handlebars = Handlebars::new();
+    register_helpers(&mut handlebars);
+    let syntax = |path: &str, e: handlebars::TemplateError| {
+        AssistantError::Workflow(format!("{}: {}", path, e.reason()))
+    };
+    for partial in partials {
+        let path = partial.display().to_string();
+        let name = partial
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        handlebars
+            .register_partial(&name, read_template(&path)?)
+            .map_err(|e| syntax(&path, e))?;
+    }
+    for item in workflow.values().flat_map(|hm| hm.values()) {
+        for (path, template) in [
+            (&item.request.path, &item.request.template),
+            (&item.response.path, &item.response.template),
+        ] {
+            if let Some(template) = template {
+                handlebars
+                    .register_template_string(path, template)
+                    .map_err(|e| syntax(path, e))?;
+            }
         }
     }
+    Ok(Templates::new(handlebars))
 }
 
 struct Model<'a> {
@@ -397,9 +1239,136 @@ struct Model<'a> {
     // Result edit_area will be used for displaying the result of AI.
     edit_areas: Vec<EditArea>,
     current: (String, String),
-    workflow: Workflow<RenderingContext<'a>, String, Request, Response>,
-    // handlebars is setup from workflow on new(). It stores  path -> template mapping.
-    handlebars: Handlebars<'a>,
+    workflow: Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    // handlebars stores path -> template mapping, the partials and the helpers.
+    handlebars: Templates<'a>,
+    // last error or progress shown under the editors
+    status: String,
+    // cancels the pending QueryAi
+    cancel: Cancel,
+    // a query or a fan-out is running, so that another one waits for it
+    asking: bool,
+    // toolchains keyed by code fence language
+    toolchains: Toolchains,
+    // --markers, used for the items which have none
+    markers: Vec<Regex>,
+    // the rendered response, shown unless the processed blocks are
+    raw_response: String,
+    show_processed: bool,
+    // the list answer of (name, tag) as checkboxes, grouped by category
+    selection: HashMap<String, Vec<(String, bool)>>,
+    selected_from: (AssistantName, Tag),
+    // to attach to the threads of an opened session
+    backends: Backends,
+    // items asked by themselves, up to --max-steps
+    steps: usize,
+}
+
+impl<'a> Model<'a> {
+    /// Records the build of the answer of `(name, tag)` and what was run on it.
+    /// Items which build their answer go on depending on the result.
+    fn checked(&mut self, name: AssistantName, tag: Tag, talks: Vec<Talk>) -> Command<Message> {
+        for talk in talks {
+            push_talk(&mut self.conversations, talk);
+        }
+        let next = match get_item(&self.workflow, &name, &tag) {
+            Some(item) if item.next.needs_build() => {
+                next_by_answer(&mut self.workflow, &name, &tag, &self.conversations)
+            }
+            _ => None,
+        };
+        self.go_to(next)
+    }
+
+    /// Asks the items of `to` at the same time.
+    fn fan_out(&mut self, to: &[Target], join: Target) -> Command<Message> {
+        let Some(context) = self.context.clone() else {
+            return Command::none();
+        };
+        if self.asking {
+            self.status = "busy, fan out once answered".to_string();
+            return Command::none();
+        }
+        let queries = fan_out_queries(
+            &self.handlebars,
+            &self.workflow,
+            &self.prompts,
+            &mut self.conversations,
+            to,
+        );
+        match queries {
+            Ok(queries) => {
+                self.cancel = Cancel::default();
+                self.asking = true;
+                self.status = format!("asking {} at once", queries.len());
+                Command::perform(
+                    ask_all(context, queries, self.cancel.clone()),
+                    move |answers| Message::FannedOut { join, answers },
+                )
+            }
+            Err(e) => {
+                self.status = e.to_string();
+                Command::none()
+            }
+        }
+    }
+
+    /// Everything `restore` needs to show the workflow as it is now, or None
+    /// while a query is running.
+    fn session(&self) -> Option<SavedSession> {
+        if self.asking {
+            return None;
+        }
+        let sessions = match &self.context {
+            Some(context) => context.try_lock().ok()?.sessions(),
+            None => HashMap::new(),
+        };
+        Some(SavedSession {
+            conversations: self.conversations.clone(),
+            current: Some(self.current.clone()),
+            counters: counters(&self.workflow),
+            input: self.edit_areas[AreaIndex::Input as usize].content.text(),
+            result: self.raw_response.clone(),
+            sessions,
+        })
+    }
+
+    /// Shows a saved session. Its threads are attached by `connect`.
+    fn restore(&mut self, session: SavedSession) {
+        self.conversations = session.conversations;
+        if let Some(current) = session.current {
+            self.current = current;
+        }
+        restore_counters(&mut self.workflow, &session.counters);
+        set_editor_contents(&mut self.edit_areas, AreaIndex::Input, &session.input);
+        let editable = get_item(&self.workflow, &self.current.0, &self.current.1)
+            .is_some_and(|item| is_editable_state(&item));
+        self.edit_areas[AreaIndex::Input as usize].is_editable = editable;
+        self.raw_response = session.result;
+        self.selection = HashMap::new();
+        self.show_result();
+    }
+
+    /// The processed blocks of the current item in the Result pane, or the
+    /// rendered response if there are none or the raw view is chosen.
+    fn show_result(&mut self) {
+        let (name, tag) = &self.current;
+        let processed = processed_text(&self.conversations, name, tag);
+        let text = match processed {
+            Some(text) if self.show_processed => text,
+            _ => self.raw_response.clone(),
+        };
+        set_editor_contents(&mut self.edit_areas, AreaIndex::Result, &text);
+    }
+
+    fn go_to(&self, next: Option<(AssistantName, Tag)>) -> Command<Message> {
+        match next {
+            Some((name, tag)) => Command::perform(next_state(name, tag), |(name, tag)| {
+                Message::LoadInput { name, tag }
+            }),
+            None => Command::none(),
+        }
+    }
 }
 
 fn push_talk(conversations: &mut Vec<Talk>, talk: Talk) {
@@ -418,61 +1387,158 @@ pub enum AssistantError {
     #[error("file already exists for the directory")]
     FileExists(),
 
-    #[error("file open error")]
+    #[error("file open error: {0}")]
     FileOpenFailed(String),
 
-    #[error("IO error")]
-    IoError,
+    #[error("template missing: {0}")]
+    TemplateMissing(String),
+
+    #[error("IO error: {0}")]
+    IoError(String),
+
+    #[error("API call error: {0}")]
+    APIError(String),
 
-    #[error("API call error")]
-    APIError,
+    #[error("{0}")]
+    OpenAIApi(OpenAIApiError),
+
+    #[error("config error: {0}")]
+    Config(config::ConfigError),
+
+    #[error("invalid config key: {0}")]
+    InvalidConfigKey(String),
+
+    #[error("invalid marker: {0}")]
+    Regex(String),
+
+    #[error("invalid workflow: {0}")]
+    Workflow(String),
+
+    #[error("template {0}: {1}")]
+    Render(String, String),
+
+    #[error("GUI error: {0}")]
+    Gui(String),
+
+    #[error("answer does not follow the schema: {0}")]
+    InvalidJson(String),
+}
 
-    #[error("API access failed")]
-    AppAccessError,
+impl AssistantError {
+    /// Process exit code for CLI use.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            AssistantError::Config(_)
+            | AssistantError::InvalidConfigKey(_)
+            | AssistantError::Regex(_) => 2,
+            AssistantError::FileExists()
+            | AssistantError::FileOpenFailed(_)
+            | AssistantError::TemplateMissing(_)
+            | AssistantError::IoError(_) => 3,
+            AssistantError::Workflow(_) | AssistantError::Render(..) => 4,
+            AssistantError::Gui(_) => 5,
+            AssistantError::InvalidJson(_) => 6,
+            AssistantError::APIError(_) => 10,
+            AssistantError::OpenAIApi(e) => match e {
+                OpenAIApiError::AuthFailed(_) => 11,
+                OpenAIApiError::RateLimited(_) => 12,
+                OpenAIApiError::RunFailed { .. } => 13,
+                OpenAIApiError::RunExpired | OpenAIApiError::Timeout => 14,
+                OpenAIApiError::RunCancelled | OpenAIApiError::RunIncomplete(_) => 15,
+                OpenAIApiError::RequiresAction(_) => 16,
+                OpenAIApiError::Refused(_) => 17,
+                OpenAIApiError::UnknownAssistant(_) => 4,
+                OpenAIApiError::InvalidConfigKey(_) => 2,
+                OpenAIApiError::Io(_) => 3,
+                _ => 10,
+            },
+        }
+    }
 }
 
 impl From<iced::Error> for AssistantError {
     fn from(err: iced::Error) -> AssistantError {
-        dbg!(err);
-        AssistantError::AppAccessError
+        AssistantError::Gui(err.to_string())
     }
 }
 
 impl From<openai_api::OpenAIApiError> for AssistantError {
     fn from(error: openai_api::OpenAIApiError) -> AssistantError {
-        dbg!(error);
-        AssistantError::AppAccessError
+        AssistantError::OpenAIApi(error)
     }
 }
 impl From<std::io::Error> for AssistantError {
     fn from(error: std::io::Error) -> AssistantError {
-        dbg!(&error);
+        debug!("{:?}", &error);
         match error.kind() {
             io::ErrorKind::AlreadyExists => AssistantError::FileExists(),
-            _ => AssistantError::IoError,
+            _ => AssistantError::IoError(error.to_string()),
         }
     }
 }
 
 impl From<config::ConfigError> for AssistantError {
     fn from(error: config::ConfigError) -> AssistantError {
-        dbg!(error);
-        AssistantError::AppAccessError
+        AssistantError::Config(error)
     }
 }
 
 impl From<regex::Error> for AssistantError {
     fn from(error: regex::Error) -> AssistantError {
-        dbg!(error);
-        AssistantError::AppAccessError
+        AssistantError::Regex(error.to_string())
     }
 }
 
-async fn save_and_compile(output_path: PathBuf, code: String) -> Result<Output, AssistantError> {
-    tokio::fs::write(&output_path, code).await?;
-    let res = compile(output_path).await?;
+// once it compiles, runs the tests in the sandbox, or the program with no input
+// if there is no test
+async fn save_and_compile(
+    toolchain: Toolchain,
+    dir: PathBuf,
+    code: String,
+    tests: Vec<TestCase>,
+) -> Result<(Build, Option<Execution>, Option<TestReport>), AssistantError> {
+    let build = toolchain.build(&dir, &code).await?;
+    if !build.success {
+        return Ok((build, None, None));
+    }
+    if tests.is_empty() {
+        let execution = toolchain.run(&build, "").await?;
+        Ok((build, Some(execution), None))
+    } else {
+        let report = run_tests(&toolchain, &build, &tests).await?;
+        Ok((build, None, Some(report)))
+    }
+}
 
-    Ok(res)
+/// What `save_and_compile` did, as talks of `(name, tag)`.
+fn build_talks(
+    name: &AssistantName,
+    tag: &Tag,
+    lang: String,
+    (build, execution, report): (Build, Option<Execution>, Option<TestReport>),
+) -> Vec<Talk> {
+    let mut talks = vec![Talk::Build {
+        name: name.clone(),
+        tag: tag.clone(),
+        lang,
+        success: build.success,
+        output: build.output,
+    }];
+    if let Some(execution) = execution {
+        talks.push(Talk::Run {
+            name: name.clone(),
+            tag: tag.clone(),
+            execution,
+        });
+    }
+    if let Some(report) = report {
+        talks.push(Talk::Tests {
+            name: name.clone(),
+            tag: tag.clone(),
+            report,
+        });
+    }
+    talks
 }
 
 fn set_editor_contents(area: &mut Vec<EditArea>, idx: AreaIndex, text: &str) {
@@ -483,8 +1549,14 @@ fn set_editor_contents(area: &mut Vec<EditArea>, idx: AreaIndex, text: &str) {
     };
 }
 
+fn append_editor_contents(area: &mut [EditArea], idx: AreaIndex, text: &str) {
+    let content = &mut area[idx as usize].content;
+    content.perform(Action::Move(Motion::DocumentEnd));
+    content.perform(Action::Edit(Edit::Paste(Arc::new(text.to_string()))));
+}
+
 fn get_next<'a>(
-    wf: &Workflow<RenderingContext<'a>, String, Request, Response>,
+    wf: &Workflow<RenderingContext<'a>, Rendered, Request, Response>,
     name: &AssistantName,
     tag: &Tag,
 ) -> Option<(AssistantName, Tag)> {
@@ -504,7 +1576,168 @@ fn get_next<'a>(
     opt
 }
 
-fn is_editable_state<'a>(item: &Item<RenderingContext<'a>, String, Request, Response>) -> bool {
+/// Where a `Repair` item goes after its answer is built: on when the build
+/// succeeded, back to the item while retries are left, otherwise nowhere.
+fn next_repair<'a>(
+    wf: &mut Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    name: &AssistantName,
+    tag: &Tag,
+    success: bool,
+) -> Option<(AssistantName, Tag)> {
+    let item = wf.get_mut(name).and_then(|hm| hm.get_mut(tag))?;
+    match &mut item.next {
+        StateTrans::Repair {
+            name: next_name,
+            tag: next_tag,
+            ..
+        } if success => Some((next_name.clone(), next_tag.clone())),
+        StateTrans::Repair { retries: 0, .. } => {
+            warn!("({}, {}) does not build, no retries left", name, tag);
+            None
+        }
+        StateTrans::Repair { retries, .. } => {
+            *retries -= 1;
+            Some((name.clone(), tag.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Whether the last build of the answer of `(name, tag)` succeeded, and
+/// whether the tests run on it, if any, passed as well.
+fn last_check(conversations: &[Talk], name: &AssistantName, tag: &Tag) -> Option<(bool, bool)> {
+    let mut passed = true;
+    for talk in conversations.iter().rev() {
+        match talk {
+            Talk::Tests {
+                name: n,
+                tag: t,
+                report,
+            } if (n, t) == (name, tag) => passed &= report.failed() == 0,
+            Talk::Build {
+                name: n,
+                tag: t,
+                success,
+                ..
+            } if (n, t) == (name, tag) => return Some((*success, *success && passed)),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// The answer as JSON, or the first code block of it.
+fn parse_json(answer: &str) -> Option<serde_json::Value> {
+    serde_json::from_str(answer.trim())
+        .ok()
+        .or_else(|| extract_code(answer).and_then(|(_, code)| serde_json::from_str(&code).ok()))
+}
+
+fn holds(guard: &Guard, conversations: &[Talk], name: &AssistantName, tag: &Tag) -> bool {
+    let answer = || last_answer(conversations, name, tag);
+    match guard {
+        Guard::Matches(pattern) => match Regex::new(pattern) {
+            Ok(re) => answer().is_some_and(|a| re.is_match(&a)),
+            Err(e) => {
+                error!("invalid guard {}: {}", pattern, e);
+                false
+            }
+        },
+        Guard::Json { pointer, equals } => last_json(conversations, name, tag)
+            .or_else(|| answer().and_then(|a| parse_json(&a)))
+            .is_some_and(|v| v.pointer(pointer) == Some(equals)),
+        Guard::Built(built) => last_check(conversations, name, tag).map(|(b, _)| b) == Some(*built),
+        Guard::Passed(passed) => {
+            last_check(conversations, name, tag).is_some_and(|(_, p)| p) == *passed
+        }
+        Guard::Iterations(n) => {
+            let answered = conversations
+                .iter()
+                .filter(
+                    |t| matches!(t, Talk::FromAi { name: n, tag: t, .. } if (n, t) == (name, tag)),
+                )
+                .count();
+            answered >= *n
+        }
+        Guard::Not { guard } => !holds(guard, conversations, name, tag),
+    }
+}
+
+/// Where an item goes when its transition depends on the answer: `Repair` and
+/// `Test` on the last build, `Branch` on the first edge whose guard holds.
+fn next_by_answer<'a>(
+    wf: &mut Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    name: &AssistantName,
+    tag: &Tag,
+    conversations: &[Talk],
+) -> Option<(AssistantName, Tag)> {
+    let check = last_check(conversations, name, tag);
+    let built = check.is_some_and(|(built, _)| built);
+    let passed = check.is_some_and(|(_, passed)| passed);
+    let target = match get_item(wf, name, tag)?.next {
+        StateTrans::Repair { .. } => return next_repair(wf, name, tag, built),
+        StateTrans::Test { passed: next, .. } if passed => next,
+        StateTrans::Test { failed: next, .. } => next,
+        StateTrans::Branch { edges, default } => edges
+            .into_iter()
+            .find(|e| holds(&e.when, conversations, name, tag))
+            .map_or(default, |e| e.to),
+        _ => None,
+    };
+    target.map(|t| (t.name, t.tag))
+}
+
+/// What is left of the counters of each item.
+fn counters<'a>(
+    wf: &Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+) -> BTreeMap<AssistantName, BTreeMap<Tag, Counters>> {
+    let mut res: BTreeMap<AssistantName, BTreeMap<Tag, Counters>> = BTreeMap::new();
+    for (name, hm) in wf {
+        for (tag, item) in hm {
+            let counters = Counters {
+                auto: match item.next {
+                    StateTrans::Next { auto, .. } => auto,
+                    _ => None,
+                },
+                retries: match item.next {
+                    StateTrans::Repair { retries, .. } => Some(retries),
+                    _ => None,
+                },
+                json_retries: item.json.as_ref().map(|j| j.retries),
+            };
+            if counters != Counters::default() {
+                res.entry(name.clone())
+                    .or_default()
+                    .insert(tag.clone(), counters);
+            }
+        }
+    }
+    res
+}
+
+fn restore_counters<'a>(
+    wf: &mut Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    counters: &BTreeMap<AssistantName, BTreeMap<Tag, Counters>>,
+) {
+    for (name, hm) in counters {
+        for (tag, saved) in hm {
+            let Some(item) = wf.get_mut(name).and_then(|hm| hm.get_mut(tag)) else {
+                warn!("({}, {}) of the session is not in the workflow", name, tag);
+                continue;
+            };
+            match &mut item.next {
+                StateTrans::Next { auto, .. } if saved.auto.is_some() => *auto = saved.auto,
+                StateTrans::Repair { retries, .. } => *retries = saved.retries.unwrap_or(*retries),
+                _ => (),
+            }
+            if let (Some(json), Some(retries)) = (&mut item.json, saved.json_retries) {
+                json.retries = retries;
+            }
+        }
+    }
+}
+
+fn is_editable_state<'a>(item: &Item<RenderingContext<'a>, Rendered, Request, Response>) -> bool {
     match item.next {
         StateTrans::Wait { .. } => true,
         _ => false,
@@ -512,7 +1745,7 @@ fn is_editable_state<'a>(item: &Item<RenderingContext<'a>, String, Request, Resp
 }
 
 fn dec_auto<'a>(
-    wf: &mut Workflow<RenderingContext<'a>, String, Request, Response>,
+    wf: &mut Workflow<RenderingContext<'a>, Rendered, Request, Response>,
     name: &AssistantName,
     tag: &Tag,
 ) {
@@ -562,68 +1795,75 @@ impl<'a> Application for Model<'a> {
     type Executor = iced::executor::Default;
     type Flags = (
         Cli,
-        OpenAi,
+        Backends,
         HashMap<String, Box<Prompt>>,
-        Workflow<RenderingContext<'a>, String, Request, Response>,
-        Option<CClient>,
+        Workflow<RenderingContext<'a>, Rendered, Request, Response>,
         (AssistantName, Tag),
+        Vec<Session>,
+        Toolchains,
+        Templates<'a>,
+        Option<SavedSession>,
     );
 
     fn new(flags: <Model<'a> as iced::Application>::Flags) -> (Model<'a>, Command<Message>) {
         let workflow = flags.3;
-        let (name, tag) = flags.5;
+        let (name, tag) = flags.4;
         let prompt = EditArea::default();
         let input = EditArea::default();
         let result = EditArea::default();
 
-        // As OpenAICOnfig and AzureConfig cannot co-exists in a generic way,
-        // we need to use conditional compilation.
-        // It might be good to place this in main() but it introduces a lot of
-        // conditional compilation.
-        #[cfg(not(azure_ai))]
-        //let client = create_opeai_client(&flags.1);
-        let client = flags.1.create_client();
-        #[cfg(azure_ai)]
-        let client = create_opeai_client(&flags.1);
         let assistant_names = flags.2.keys().cloned().collect::<Vec<_>>();
+        let resumed = flags.8;
         // Initialize EditArea with loaded input.
         // To allow font loading the variable is mutable
-        let mut commands: Vec<Command<Message>> = vec![
-            // To triger initial sreen
-            Command::perform(next_state(name.clone(), tag.clone()), |(name, tag)| {
-                Message::LoadInput { name, tag }
-            }),
-            Command::perform(
-                connect(
-                    flags.1.clone(),
-                    client.unwrap(),
-                    assistant_names,
-                    flags.2.clone(),
-                ),
-                Message::Connected,
+        let mut commands: Vec<Command<Message>> = vec![Command::perform(
+            connect(
+                flags.1.clone(),
+                flags.0.config_key.clone(),
+                assistant_names,
+                flags.2.clone(),
+                flags.5,
             ),
-        ];
-        let mut handlebars = Handlebars::new();
-        register_template(&mut handlebars, &workflow);
+            Message::Connected,
+        )];
+        // To triger initial sreen, unless a session is shown as it was saved
+        if resumed.is_none() {
+            commands.push(Command::perform(
+                next_state(name.clone(), tag.clone()),
+                |(name, tag)| Message::LoadInput { name, tag },
+            ));
+        }
         #[cfg(feature = "load_font")]
         commands.push(
             font::load(include_bytes!("../fonts/UDEVGothic-Regular.ttf").as_slice())
                 .map(Message::FontLoaded),
         );
 
-        (
-            Model {
-                env: flags.0.clone(),
-                prompts: flags.2.clone(),
-                context: None,
-                edit_areas: vec![prompt, input, result],
-                current: (name.clone(), tag.clone()),
-                workflow: workflow,
-                conversations: vec![],
-                handlebars: handlebars,
-            },
-            Command::<Message>::batch(commands),
-        )
+        let mut model = Model {
+            env: flags.0.clone(),
+            prompts: flags.2.clone(),
+            context: None,
+            edit_areas: vec![prompt, input, result],
+            current: (name.clone(), tag.clone()),
+            workflow,
+            conversations: vec![],
+            handlebars: flags.7,
+            status: "connecting".to_string(),
+            cancel: Cancel::default(),
+            toolchains: flags.6,
+            markers: flags.0.get_markers().unwrap_or_default(),
+            raw_response: String::new(),
+            show_processed: true,
+            selection: HashMap::new(),
+            selected_from: (String::new(), String::new()),
+            backends: flags.1,
+            steps: 0,
+            asking: false,
+        };
+        if let Some(session) = resumed {
+            model.restore(session);
+        }
+        (model, Command::<Message>::batch(commands))
     }
 
     fn title(&self) -> String {
@@ -636,11 +1876,21 @@ impl<'a> Application for Model<'a> {
         let command = match message {
             Message::Connected(Ok(ctx)) => {
                 info!("Connected: {:?}", &ctx);
+                ctx.restore_history(&self.conversations);
+                self.status = match sessions::write_sessions(&self.env.output_dir, &ctx.sessions())
+                {
+                    Ok(()) => "connected".to_string(),
+                    Err(e) => format!("connected, sessions not saved: {}", e),
+                };
                 self.context = Some(Arc::new(Mutex::new(ctx)));
                 //next_current = Some((self.current.0.clone(), self.current.1.clone()));
                 Command::none()
             }
-            Message::Connected(Err(_)) => Command::none(),
+            Message::Connected(Err(e)) => {
+                error!("Connection failed: {:?}", &e);
+                self.status = format!("connection failed: {}", e);
+                Command::none()
+            }
 
             Message::LoadInput { name, tag } => {
                 info!("({:?}, {:?})", &name, &tag);
@@ -653,12 +1903,20 @@ impl<'a> Application for Model<'a> {
                 if let (Some(item), Some((instruction, Some(input)))) = (item, input) {
                     debug!("{:?}", item);
                     debug!("{:?}", input);
-                    let input_displayed = item.request.render((
+                    let input_displayed = match item.request.render((
                         &self.handlebars,
                         &self.conversations,
                         &instruction,
                         &input,
-                    ));
+                        (&name, &tag),
+                    )) {
+                        Ok(rendered) => rendered,
+                        Err(e) => {
+                            error!("{}", e);
+                            self.status = e.to_string();
+                            return Command::none();
+                        }
+                    };
                     self.edit_areas[AreaIndex::Input as usize].content =
                         text_editor::Content::with_text(&input_displayed);
                     self.edit_areas[AreaIndex::Input as usize].is_editable =
@@ -672,6 +1930,12 @@ impl<'a> Application for Model<'a> {
                         },
                     );
                     self.current = (name.clone(), tag.clone());
+                    if self.steps >= self.env.max_steps() {
+                        warn!("max steps {} reached at ({}, {})", self.steps, &name, &tag);
+                        self.status = "max steps reached, press Ask AI to go on".to_string();
+                        return Command::none();
+                    }
+                    self.steps += 1;
                     Command::perform(next_state(name, tag), |pair| Message::QueryAi {
                         name: pair.0,
                         tag: pair.1,
@@ -681,6 +1945,10 @@ impl<'a> Application for Model<'a> {
                 }
             }
 
+            Message::QueryAi { .. } if self.asking => {
+                self.status = "busy, ask once answered".to_string();
+                Command::none()
+            }
             Message::QueryAi { name, tag } => {
                 if let Some(context) = self.context.clone() {
                     let input = self.edit_areas[AreaIndex::Input as usize].content.text();
@@ -693,16 +1961,33 @@ impl<'a> Application for Model<'a> {
                         },
                     );
                     set_editor_contents(&mut self.edit_areas, AreaIndex::Result, "");
-
-                    Command::perform(ask(context, name, tag, input), move |answer| {
-                        Message::Answered { answer }
+                    self.cancel = Cancel::default();
+                    self.asking = true;
+                    self.status = format!("asking: ({}, {})", &name, &tag);
+
+                    let answers = ask_stream(context, name, tag, input, self.cancel.clone());
+                    Command::run(answers, |event| match event {
+                        Ok((name, tag, StreamEvent::Delta(delta))) => {
+                            Message::AnswerDelta { name, tag, delta }
+                        }
+                        Ok((name, tag, StreamEvent::Completed(answer))) => Message::Answered {
+                            answer: Ok((name, tag, answer)),
+                        },
+                        Ok((_, _, StreamEvent::RunCreated(_))) => Message::DoNothing,
+                        Err(e) => Message::Answered { answer: Err(e) },
                     })
                 } else {
                     Command::none()
                 }
             }
+            Message::AnswerDelta { name, tag, delta } => {
+                if (name, tag) == self.current {
+                    append_editor_contents(&mut self.edit_areas, AreaIndex::Result, &delta);
+                }
+                Command::none()
+            }
             Message::Answered {
-                answer: Ok((name, tag, text)),
+                answer: Ok((name, tag, answer)),
                 ..
             } => {
                 let item = get_item(&self.workflow, &name, &tag);
@@ -710,28 +1995,72 @@ impl<'a> Application for Model<'a> {
                     .prompts
                     .get(&name)
                     .map(|p| (&p.instruction, p.inputs.get(&tag)));
-                debug!("text:{:?}", &text);
-                push_talk(
-                    &mut self.conversations,
-                    Talk::FromAi {
-                        name: name.clone(),
-                        tag: tag.clone(),
-                        message: Content::Text(text),
-                    },
-                );
+                debug!("answer:{:?}", &answer);
+                self.asking = false;
+                self.status = format!("answered: ({}, {})", &name, &tag);
+                for talk in answer_talks(&name, &tag, answer) {
+                    push_talk(&mut self.conversations, talk);
+                }
+                match check_json(&mut self.workflow, &name, &tag, &self.conversations) {
+                    Some(JsonCheck::Valid(talk)) => push_talk(&mut self.conversations, talk),
+                    Some(JsonCheck::Retry(query)) => {
+                        self.status = format!("({}, {}) does not follow the schema", &name, &tag);
+                        set_editor_contents(&mut self.edit_areas, AreaIndex::Input, &query);
+                        return Command::perform(next_state(name, tag), |(name, tag)| {
+                            Message::QueryAi { name, tag }
+                        });
+                    }
+                    Some(JsonCheck::Invalid(errors)) => {
+                        self.status = format!("({}, {}): {}", &name, &tag, errors);
+                        return Command::none();
+                    }
+                    None => (),
+                }
+                let markers = item.as_ref().map_or(self.markers.clone(), |item| {
+                    item_markers(item, &self.markers)
+                });
+                for talk in processed_talks(&self.conversations, &name, &tag, &markers) {
+                    push_talk(&mut self.conversations, talk);
+                }
+                let json = last_json(&self.conversations, &name, &tag).or_else(|| {
+                    last_answer(&self.conversations, &name, &tag)
+                        .and_then(|answer| extract_json(&answer).ok())
+                });
+                self.selection = json.as_ref().and_then(to_choices).unwrap_or_default();
+                self.selected_from = (name.clone(), tag.clone());
 
                 if let (Some(item), Some((instruction, Some(input)))) = (item, input) {
-                    let response_text = item.response.render((
-                        &self.handlebars,
-                        &self.conversations,
-                        &instruction,
-                        &input,
-                    ));
+                    let response_text = item
+                        .response
+                        .render((
+                            &self.handlebars,
+                            &self.conversations,
+                            instruction,
+                            input,
+                            (&name, &tag),
+                        ))
+                        .unwrap_or_else(|e| {
+                            error!("{}", e);
+                            self.status = e.to_string();
+                            String::new()
+                        });
 
-                    set_editor_contents(&mut self.edit_areas, AreaIndex::Result, &response_text);
                     debug!("response_text:{:?}", response_text);
+                    self.raw_response = response_text;
+                    self.show_result();
                     dec_auto(&mut self.workflow, &name, &tag);
-                    if let Some((name, tag)) = get_next(&self.workflow, &name, &tag) {
+                    if item.next.needs_build() {
+                        Command::perform(next_state(name, tag), |(name, tag)| Message::Compile {
+                            name,
+                            tag,
+                        })
+                    } else if let StateTrans::Branch { .. } = item.next {
+                        let next =
+                            next_by_answer(&mut self.workflow, &name, &tag, &self.conversations);
+                        self.go_to(next)
+                    } else if let StateTrans::FanOut { to, join } = item.next {
+                        self.fan_out(&to, join)
+                    } else if let Some((name, tag)) = get_next(&self.workflow, &name, &tag) {
                         info!("Answered: ({:?},{:?})", &name, &tag);
                         Command::perform(next_state(name.clone(), tag.clone()), |(name, tag)| {
                             Message::LoadInput {
@@ -746,8 +2075,122 @@ impl<'a> Application for Model<'a> {
                     Command::none()
                 }
             }
-            Message::Answered { answer: Err(_), .. } => {
-                error!("FAILED");
+            Message::Answered {
+                answer: Err((name, e)),
+                ..
+            } => {
+                error!("FAILED: {}: {:?}", &name, &e);
+                self.asking = false;
+                self.status = format!("{}: {}", name, e);
+                Command::none()
+            }
+            Message::CancelAi => {
+                self.cancel.cancel();
+                self.status = "cancelling".to_string();
+                Command::none()
+            }
+            Message::Compile { name, tag } => {
+                match code_to_build(&self.toolchains, &self.conversations, &name, &tag) {
+                    Ok((lang, toolchain, code)) => {
+                        let toolchain = toolchain.clone();
+                        let tests = self
+                            .prompts
+                            .get(&name)
+                            .and_then(|p| p.inputs.get(&tag))
+                            .map(|i| i.tests.clone())
+                            .unwrap_or_default();
+                        self.status = format!("compiling {}: ({}, {})", &lang, &name, &tag);
+                        let dir = build_dir(&self.env.output_dir, &name, &tag);
+                        Command::perform(
+                            save_and_compile(toolchain, dir, code, tests),
+                            move |result| Message::Compiled {
+                                name,
+                                tag,
+                                lang,
+                                result,
+                            },
+                        )
+                    }
+                    Err((lang, reason)) => {
+                        self.status = reason.clone();
+                        let build = Talk::Build {
+                            name: name.clone(),
+                            tag: tag.clone(),
+                            lang,
+                            success: false,
+                            output: reason,
+                        };
+                        self.checked(name, tag, vec![build])
+                    }
+                }
+            }
+            Message::Compiled {
+                name,
+                tag,
+                lang,
+                result,
+            } => match result {
+                Ok((build, execution, report)) => {
+                    let mut text = format!("--- build {:?}\n{}", &build.source, &build.output);
+                    if let Some(execution) = &execution {
+                        text.push_str(&format!("--- run\n{}", execution.to_text()));
+                    }
+                    if let Some(report) = &report {
+                        text.push_str(&format!(
+                            "--- tests: {}\n{}",
+                            report.summary(),
+                            report.failures()
+                        ));
+                    }
+                    self.status = match (&execution, &report) {
+                        (Some(execution), _) if !execution.success() => {
+                            format!("run failed: ({}, {})", &name, &tag)
+                        }
+                        (_, Some(report)) => {
+                            format!("tests {}: ({}, {})", report.summary(), &name, &tag)
+                        }
+                        _ => format!(
+                            "build {}: ({}, {})",
+                            if build.success { "succeeded" } else { "failed" },
+                            &name,
+                            &tag
+                        ),
+                    };
+                    set_editor_contents(&mut self.edit_areas, AreaIndex::Result, &text);
+                    let talks = build_talks(&name, &tag, lang, (build, execution, report));
+                    self.checked(name, tag, talks)
+                }
+                Err(e) => {
+                    error!("Build failed: {:?}", &e);
+                    self.status = format!("build failed: {}", e);
+                    Command::none()
+                }
+            },
+            Message::FannedOut {
+                join,
+                answers: Ok(answers),
+            } => {
+                self.asking = false;
+                for talk in joined_talks(answers, &join) {
+                    push_talk(&mut self.conversations, talk);
+                }
+                let text = fan_out_answers(&self.conversations);
+                set_editor_contents(&mut self.edit_areas, AreaIndex::Result, &text);
+                self.status = format!("joined: ({}, {})", &join.name, &join.tag);
+                self.go_to(Some((join.name, join.tag)))
+            }
+            Message::FannedOut {
+                answers: Err((name, e, answered)),
+                ..
+            } => {
+                error!("FAILED: {}: {:?}", &name, &e);
+                self.asking = false;
+                for (name, tag, answer) in answered {
+                    for talk in answer_talks(&name, &tag, answer) {
+                        push_talk(&mut self.conversations, talk);
+                    }
+                }
+                self.status = format!("{}: {}", name, e);
                 Command::none()
             }
             Message::ActionPerformed((index, action)) => {
@@ -765,17 +2208,63 @@ impl<'a> Application for Model<'a> {
                 Command::none()
             }
 
-            Message::SaveConversation { outut_dir } => {
-                let convs = self.conversations.clone();
-                let output_dir_path = PathBuf::from(outut_dir);
-                let _ = serde_yaml::to_string(&convs).map(|s| {
-                    let output_path = output_dir_path.join("conversation.yaml");
-                    fs::write(output_path, s)
-                });
-
+            Message::SaveSession => {
+                let path = self.env.session_path();
+                self.status = match self.session() {
+                    None => "busy, save once answered".to_string(),
+                    Some(session) => match write_session(&path, &session) {
+                        Ok(()) => format!("saved {}", path.display()),
+                        Err(e) => format!("not saved: {}", e),
+                    },
+                };
+                Command::none()
+            }
+            Message::OpenSession => {
+                let path = self.env.session_path();
+                match read_session(&path) {
+                    Ok(session) => {
+                        let saved = session.sessions.values().cloned().collect();
+                        self.restore(session);
+                        self.status = format!("opened {}, connecting", path.display());
+                        Command::perform(
+                            connect(
+                                self.backends.clone(),
+                                self.env.config_key.clone(),
+                                self.prompts.keys().cloned().collect(),
+                                self.prompts.clone(),
+                                saved,
+                            ),
+                            Message::Connected,
+                        )
+                    }
+                    Err(e) => {
+                        self.status = e.to_string();
+                        Command::none()
+                    }
+                }
+            }
+            Message::Toggled(category, i, checked) => {
+                if let Some(choice) = self.selection.get_mut(&category).and_then(|v| v.get_mut(i)) {
+                    choice.1 = checked;
+                }
+                let (name, tag) = self.selected_from.clone();
+                let talk = Talk::Selected {
+                    name,
+                    tag,
+                    items: pick_selected(&self.selection),
+                };
+                // only the last selection matters
+                match self.conversations.last_mut() {
+                    Some(last @ Talk::Selected { .. }) => *last = talk,
+                    _ => push_talk(&mut self.conversations, talk),
+                }
+                Command::none()
+            }
+            Message::ShowProcessed(show) => {
+                self.show_processed = show;
+                self.show_result();
                 Command::none()
             }
-            Message::Toggled(_string, _usize, _bool) => Command::none(),
             Message::FontLoaded(_) => Command::none(),
             Message::DoNothing => Command::none(),
         };
@@ -800,6 +2289,14 @@ impl<'a> Application for Model<'a> {
                         name: self.current.0.clone(),
                         tag: self.current.1.clone(),
                     }),
+                    Button::new(Text::new("Cancel")).on_press(Message::CancelAi),
+                    Button::new(Text::new("Compile")).on_press(Message::Compile {
+                        name: self.current.0.clone(),
+                        tag: self.current.1.clone(),
+                    }),
+                    checkbox("Processed", self.show_processed).on_toggle(Message::ShowProcessed),
+                    Button::new(Text::new("Save")).on_press(Message::SaveSession),
+                    Button::new(Text::new("Open")).on_press(Message::OpenSession),
                 ]
                 .align_items(Alignment::End)
                 .width(iced::Length::Fill),
@@ -809,10 +2306,12 @@ impl<'a> Application for Model<'a> {
                     text_editor(&vec.get(AreaIndex::Input as usize).unwrap().content)
                         .on_action(|action| Message::ActionPerformed((AreaIndex::Input, action))),
                 ],
-                column![text_editor(
-                    &vec.get(AreaIndex::Result as usize).unwrap().content
-                )],
+                column![
+                    text_editor(&vec.get(AreaIndex::Result as usize).unwrap().content),
+                    to_checkboxes(self.selection.clone()),
+                ],
             ],
+            row![Text::new(&self.status)],
         ]
         .into()
     }
@@ -822,30 +2321,86 @@ async fn next_state(p0: String, p1: String) -> (String, String) {
     (p0, p1)
 }
 
-fn extract_content(_model: &mut Model, contents: Vec<Mark>) -> Option<Content> {
-    let json = String::from("json");
-    let fsharp = String::from("fsharp");
-    if let Some(Mark::Content {
-        text,
-        lang: Some(matcher),
-    }) = get_content(contents.clone())
-    {
-        if matcher == json {
-            let response = serde_json::from_str::<HashMap<String, Vec<String>>>(&text);
-            if let Ok(_resp) = response {
-                return Some(Content::Json(text));
+/// The JSON of the ```json block of the answer, or of the answer itself.
+fn extract_json(answer: &str) -> Result<serde_json::Value, String> {
+    let block = code_blocks(answer)
+        .into_iter()
+        .find(|block| block.lang.as_deref() == Some("json"));
+    let text = block.as_ref().map_or(answer.trim(), |block| &block.code);
+    serde_json::from_str(text).map_err(|e| format!("the answer has no valid JSON: {}", e))
+}
+
+/// What becomes of an answer of an item with a `json` schema.
+#[derive(Debug, PartialEq)]
+enum JsonCheck {
+    /// The JSON of the answer, recorded as a `ProcessedResponse`.
+    Valid(Talk),
+    /// The answer is asked again with this query.
+    Retry(String),
+    /// What is wrong with the answer, which cannot be asked again.
+    Invalid(String),
+}
+
+/// Validates the last answer of `(name, tag)` against the schema of its item,
+/// counting down the retries of the item. None if it has no schema.
+fn check_json<'a>(
+    wf: &mut Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    name: &AssistantName,
+    tag: &Tag,
+    conversations: &[Talk],
+) -> Option<JsonCheck> {
+    let json = wf
+        .get_mut(name)
+        .and_then(|hm| hm.get_mut(tag))?
+        .json
+        .as_mut()?;
+    let answer = last_answer(conversations, name, tag).unwrap_or_default();
+    let errors = match extract_json(&answer) {
+        Ok(value) => match schema::validate(&json.schema, &value) {
+            errors if errors.is_empty() => {
+                return Some(JsonCheck::Valid(Talk::ProcessedResponse {
+                    name: name.clone(),
+                    tag: tag.clone(),
+                    message: Content::Json(value),
+                }))
             }
-        } else if matcher == fsharp {
-            return Some(Content::Fsharp(text));
-        } else {
-            warn!("Unknown matcher: {}", &text);
-            return Some(Content::Text(text));
-        }
-        Some(Content::Text(text))
-    } else {
-        warn!("No splitted contents: {:?}", &contents);
-        None
+            errors => errors,
+        },
+        Err(e) => vec![e],
+    };
+    let errors = errors
+        .iter()
+        .map(|e| format!("- {}", e))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if json.retries == 0 {
+        warn!(
+            "({}, {}) does not follow the schema, no retries left",
+            name, tag
+        );
+        return Some(JsonCheck::Invalid(errors));
     }
+    json.retries -= 1;
+    Some(JsonCheck::Retry(format!(
+        "The JSON of your answer does not follow the schema:\n{}\n\
+         Answer again with the corrected JSON in a ```json block.",
+        errors
+    )))
+}
+
+/// The validated JSON of the last answer of `(name, tag)`, if it has one.
+fn last_json(conversations: &[Talk], name: &AssistantName, tag: &Tag) -> Option<serde_json::Value> {
+    conversations.iter().rev().find_map(|talk| match talk {
+        Talk::ProcessedResponse {
+            name: n,
+            tag: t,
+            message: Content::Json(value),
+        } if (n, t) == (name, tag) => Some(Some(value.clone())),
+        Talk::FromAi {
+            name: n, tag: t, ..
+        } if (n, t) == (name, tag) => Some(None),
+        _ => None,
+    })?
 }
 
 fn list_inputs(prompts: &HashMap<String, Box<Prompt>>) -> Vec<(String, String)> {
@@ -863,9 +2418,7 @@ fn list_inputs(prompts: &HashMap<String, Box<Prompt>>) -> Vec<(String, String)>
 
 impl From<reqwest::Error> for AssistantError {
     fn from(error: reqwest::Error) -> AssistantError {
-        dbg!(error);
-
-        AssistantError::APIError
+        AssistantError::APIError(error.to_string())
     }
 }
 
@@ -874,10 +2427,33 @@ fn button<'a>(text: &str, tag: &str) -> widget::Button<'a, Message> {
     Button::new(Text::new(title))
 }
 
+/// The categories and items of a list answer such as
+/// `{"bugs": ["a", "b"], "features": ["c"]}`, none of them checked.
+fn to_choices(json: &serde_json::Value) -> Option<HashMap<String, Vec<(String, bool)>>> {
+    let categories = json.as_object().filter(|o| !o.is_empty())?;
+    categories
+        .iter()
+        .map(|(category, items)| {
+            let items = items
+                .as_array()?
+                .iter()
+                .map(|item| match item {
+                    serde_json::Value::String(s) => (s.clone(), false),
+                    otherwise => (otherwise.to_string(), false),
+                })
+                .collect();
+            Some((category.clone(), items))
+        })
+        .collect()
+}
+
+/// The checked items, category by category in the order they are shown.
 fn pick_selected(resp: &HashMap<String, Vec<(String, bool)>>) -> Vec<String> {
     let mut vec = Vec::new();
-    for (_k, v) in resp.iter() {
-        for (s, b) in v.iter() {
+    let mut keys: Vec<_> = resp.keys().collect();
+    keys.sort();
+    for k in keys {
+        for (s, b) in resp[k].iter() {
             if *b {
                 vec.push(s.clone());
             }
@@ -1010,6 +2586,7 @@ queen:
       response:
         name: sdfg
     k2:
+      start: true
       next: !Next
         name: queen
         tag: q1
@@ -1053,6 +2630,344 @@ queen:
         "#;
         let wf: Workflow<&Vec<Talk>, String, T, T> = read_config(None, &workflow_str).unwrap();
         let parsed = parse_scenario(prompts, wf);
-        assert_eq!(parsed.is_some(), true);
+        assert!(parsed.is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let prompt_str = r#"
+king:
+  instruction: king
+  inputs:
+    k1:
+      text: k1
+    k2:
+      text: k2
+    k3:
+      text: k3
+        "#;
+        let prompts: HashMap<String, Box<Prompt>> = read_config(None, prompt_str).unwrap();
+        let workflow_str = r#"
+  king:
+    k1:
+      start: true
+      next: !Branch
+        edges:
+          - when: !Matches "("
+            to:
+              name: king
+              tag: k9
+      request:
+        path: req
+      response:
+        path: rsp
+    k2:
+      start: true
+      next: !Stop
+      markers: ["```(\\w+)", "["]
+      json:
+        schema:
+          properties:
+            score: { type: integer, exclusiveMinimum: 0 }
+          $ref: other.json
+      request:
+        path: req
+      response:
+        path: rsp
+    k4:
+      next: !FanOut
+        to:
+          - name: king
+            tag: k1
+          - name: king
+            tag: k2
+        join:
+          name: king
+          tag: k1
+      request:
+        path: req
+      response:
+        path: rsp
+        "#;
+        let wf: Workflow<RenderingContext, Rendered, Request, Response> =
+            read_config(None, workflow_str).unwrap();
+        let s = |v: &str| v.to_string();
+        let invalid = |pattern: &str| Regex::new(pattern).unwrap_err().to_string();
+        assert_eq!(
+            validate(&prompts, &wf),
+            vec![
+                Diagnostic::MissingItem(s("king"), s("k3")),
+                Diagnostic::MissingInput(s("king"), s("k4")),
+                Diagnostic::UnknownTarget(s("king"), s("k1"), s("king"), s("k9")),
+                Diagnostic::InvalidGuard(s("king"), s("k1"), s("("), invalid("(")),
+                Diagnostic::InvalidMarker(s("king"), s("k2"), s("["), invalid("[")),
+                Diagnostic::UnsupportedSchema(s("king"), s("k2"), s("/$ref")),
+                Diagnostic::UnsupportedSchema(
+                    s("king"),
+                    s("k2"),
+                    s("/properties/score/exclusiveMinimum")
+                ),
+                Diagnostic::SharedFanOut(s("king"), s("k4"), s("king")),
+                Diagnostic::MultipleStarts(s("(king, k1), (king, k2)")),
+            ]
+        );
+        assert!(parse_scenario(prompts, wf).is_err());
+    }
+
+    #[test]
+    fn test_next_repair() {
+        let workflow_str = r#"
+  king:
+    k1:
+      next: !Repair
+        retries: 1
+        name: queen
+        tag: q1
+      request:
+        path: req
+      response:
+        path: rsp
+        "#;
+        let mut wf: Workflow<RenderingContext, Rendered, Request, Response> =
+            read_config(None, workflow_str).unwrap();
+        let king = "king".to_string();
+        let k1 = "k1".to_string();
+        assert_eq!(
+            next_repair(&mut wf, &king, &k1, true),
+            Some(("queen".to_string(), "q1".to_string()))
+        );
+        assert_eq!(
+            next_repair(&mut wf, &king, &k1, false),
+            Some((king.clone(), k1.clone()))
+        );
+        // retries are used up
+        assert_eq!(next_repair(&mut wf, &king, &k1, false), None);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let build = |success: bool| Talk::Build {
+            name: "king".to_string(),
+            tag: "k1".to_string(),
+            lang: "rust".to_string(),
+            success,
+            output: "error[E0425]".to_string(),
+        };
+        let (king, k1) = (s("king"), s("k1"));
+        assert_eq!(compile_errors(&[], &king, &k1), "");
+        assert_eq!(compile_errors(&[build(false)], &king, &k1), "error[E0425]");
+        assert_eq!(compile_errors(&[build(false), build(true)], &king, &k1), "");
+        // those of another item are not fed back
+        assert_eq!(compile_errors(&[build(false)], &king, &s("k2")), "");
+    }
+
+    #[test]
+    fn test_processed_talks() {
+        let (king, k1) = ("king".to_string(), "k1".to_string());
+        let mut talks = vec![Talk::FromAi {
+            name: king.clone(),
+            tag: k1.clone(),
+            message: Content::Text("see\n```sh\necho a\n```\ndone".to_string()),
+        }];
+        let markers = vec![
+            Regex::new(r"```(\w+)\n").unwrap(),
+            Regex::new("```").unwrap(),
+        ];
+        let processed = processed_talks(&talks, &king, &k1, &markers);
+        assert_eq!(processed_talks(&talks, &king, &k1, &[]), processed);
+        assert_eq!(
+            processed,
+            vec![Talk::ProcessedResponse {
+                name: king.clone(),
+                tag: k1.clone(),
+                message: Content::Code {
+                    lang: "sh".to_string(),
+                    text: "echo a\n".to_string()
+                },
+            }]
+        );
+        assert_eq!(processed_text(&talks, &king, &k1), None);
+        talks.extend(processed);
+        assert_eq!(
+            processed_text(&talks, &king, &k1),
+            Some("--- sh\necho a\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_selection() {
+        let answer = json!({ "features": ["c"], "bugs": ["a", "b"] });
+        let mut choices = to_choices(&answer).unwrap();
+        assert_eq!(choices["bugs"], vec![(s("a"), false), (s("b"), false)]);
+        assert_eq!(to_choices(&json!({ "bugs": "a" })), None);
+        assert_eq!(to_choices(&json!(["a"])), None);
+
+        choices.get_mut("features").unwrap()[0].1 = true;
+        choices.get_mut("bugs").unwrap()[1].1 = true;
+        let items = pick_selected(&choices);
+        assert_eq!(items, vec![s("b"), s("c")]);
+
+        let talks = vec![Talk::Selected {
+            name: s("king"),
+            tag: s("k1"),
+            items,
+        }];
+        let input = Input {
+            text: s("fix"),
+            ..Input::default()
+        };
+        let mut hb = Handlebars::new();
+        register_helpers(&mut hb);
+        hb.register_template_string("req", "{{text}}:{{#each selected}} {{this}}{{/each}}")
+            .unwrap();
+        let hb = Templates::new(hb);
+        let request = Request {
+            path: s("req"),
+            template: None,
+        };
+        assert_eq!(
+            request
+                .render((&hb, &talks, &s("rule"), &input, (&s("king"), &s("k1"))))
+                .unwrap(),
+            "fix: b c"
+        );
+    }
+
+    fn s(v: &str) -> String {
+        v.to_string()
+    }
+
+    #[test]
+    fn test_template_data() {
+        let talk = |name: &str, tag: &str, text: &str| Talk::FromAi {
+            name: name.to_string(),
+            tag: tag.to_string(),
+            message: Content::Text(text.to_string()),
+        };
+        let talks = vec![
+            talk("king", "k1", "first"),
+            talk("queen", "q1", "```sh\necho a\n```"),
+            talk("king", "k1", "second"),
+            talk("king", "k2", "```sh\necho b\n```\n```py\nprint()\n```"),
+        ];
+        let input = Input {
+            prefix: None,
+            text: "text".to_string(),
+            tests: vec![],
+            test_file: None,
+        };
+        let mut hb = Handlebars::new();
+        register_helpers(&mut hb);
+        hb.register_template_string(
+            "req",
+            "{{instruction}}|{{responses.king}}|{{answers.king.k1}}|{{answers.queen.q1}}|\
+             {{iterations.king.k1}}|{{#each code_blocks}}{{lang}} {{/each}}|\
+             {{talks.0.kind}} {{talks.0.name}}|{{trim (regex last_response \"echo (.)\")}}",
+        )
+        .unwrap();
+        let hb = Templates::new(hb);
+        let request = Request {
+            path: "req".to_string(),
+            template: None,
+        };
+        let instruction = "rule".to_string();
+        assert_eq!(
+            request
+                .render((&hb, &talks, &instruction, &input, (&s("king"), &s("k2"))))
+                .unwrap(),
+            "rule|```sh\necho b\n```\n```py\nprint()\n```|second|```sh\necho a\n```|2|sh py |FromAi king|b"
+        );
+    }
+
+    #[test]
+    fn test_template_registry() {
+        let workflow_str = r#"
+  king:
+    k1:
+      start: true
+      next: !Stop
+      request:
+        template: "{{text}}|{{answers.queen.q1}}|{{build.output}}"
+      response:
+        template: "{{nope.nope}}"
+        "#;
+        let mut wf: Workflow<RenderingContext, Rendered, Request, Response> =
+            read_config(None, workflow_str).unwrap();
+        resolve_templates(&mut wf, Path::new(""));
+        let hb = template_registry(&wf, &[]).unwrap();
+        let input = Input {
+            prefix: None,
+            text: "text".to_string(),
+            tests: vec![],
+            test_file: None,
+        };
+        let item = &wf["king"]["k1"];
+        let context: RenderingContext = (&hb, &[], &s("rule"), &input, (&s("king"), &s("k1")));
+        // nothing answered or built yet
+        assert_eq!(item.request.render(context).unwrap(), "text||");
+        // a name no template is given
+        let e = item.response.render(context).unwrap_err();
+        assert!(e.to_string().contains("missing variable nope"), "{}", e);
+    }
+
+    #[test]
+    fn test_next_by_answer() {
+        let workflow_str = r#"
+  queen:
+    q1:
+      next: !Branch
+        edges:
+          - when: !Iterations 3
+          - when: !Matches ^APPROVED
+          - when: !Json
+              pointer: /verdict
+              equals: rework
+            to:
+              name: king
+              tag: k2
+          - when: !Not
+              guard: !Built true
+            to:
+              name: king
+              tag: k3
+        default:
+          name: king
+          tag: k1
+      request:
+        path: req
+      response:
+        path: rsp
+        "#;
+        let mut wf: Workflow<RenderingContext, Rendered, Request, Response> =
+            read_config(None, workflow_str).unwrap();
+        let queen = "queen".to_string();
+        let q1 = "q1".to_string();
+        let answer = |text: &str| Talk::FromAi {
+            name: queen.clone(),
+            tag: q1.clone(),
+            message: Content::Text(text.to_string()),
+        };
+        let build = Talk::Build {
+            name: queen.clone(),
+            tag: q1.clone(),
+            lang: "rust".to_string(),
+            success: true,
+            output: "".to_string(),
+        };
+        let mut next = |talks: Vec<Talk>| next_by_answer(&mut wf, &queen, &q1, &talks);
+        let king = |tag: &str| Some(("king".to_string(), tag.to_string()));
+
+        assert_eq!(next(vec![build.clone(), answer("APPROVED")]), None);
+        assert_eq!(
+            next(vec![answer("```json\n{\"verdict\": \"rework\"}\n```")]),
+            king("k2")
+        );
+        // never built
+        assert_eq!(next(vec![answer("looks good")]), king("k3"));
+        assert_eq!(next(vec![build.clone(), answer("looks good")]), king("k1"));
+        assert_eq!(
+            next(vec![answer("no"), answer("no"), build, answer("no")]),
+            None
+        );
     }
 }
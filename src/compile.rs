@@ -1,14 +1,200 @@
-use std::path::PathBuf;
-use std::process::Output;
+use crate::execute::{execute, execute_in, Execution, Sandbox};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use tokio::process::Command;
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+/// How code of one language is built and run. In the commands `{source}` is
+/// replaced with the saved source file and `{binary}` with the program to build.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Toolchain {
+    pub extension: String,
+    // interpreted languages have nothing to compile
+    #[serde(default)]
+    pub compile: Option<Vec<String>>,
+    pub run: Vec<String>,
+    // of the compiler. The program is limited by the sandbox.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+}
+
+/// Toolchains keyed by the language of the code fence, e.g. "rust" for ```rust.
+pub type Toolchains = HashMap<String, Toolchain>;
+
+/// A saved source and the result of compiling it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Build {
+    pub source: PathBuf,
+    pub binary: PathBuf,
+    pub success: bool,
+    // stdout and stderr of the compiler
+    pub output: String,
+}
+
+fn toolchain(extension: &str, compile: Option<&[&str]>, run: &[&str]) -> Toolchain {
+    let strings = |args: &[&str]| args.iter().map(|a| a.to_string()).collect();
+    Toolchain {
+        extension: extension.to_string(),
+        compile: compile.map(strings),
+        run: strings(run),
+        timeout_secs: default_timeout_secs(),
+        sandbox: Sandbox::default(),
+    }
+}
+
+pub fn default_toolchains() -> Toolchains {
+    HashMap::from([
+        (
+            "fsharp".to_string(),
+            toolchain(
+                "fs",
+                Some(&["fsharpc", "--out:{binary}.exe", "{source}"]),
+                &["mono", "{binary}.exe"],
+            ),
+        ),
+        (
+            "rust".to_string(),
+            toolchain(
+                "rs",
+                Some(&["rustc", "-o", "{binary}", "{source}"]),
+                &["{binary}"],
+            ),
+        ),
+        (
+            "c".to_string(),
+            toolchain(
+                "c",
+                Some(&["cc", "-o", "{binary}", "{source}"]),
+                &["{binary}"],
+            ),
+        ),
+        (
+            "cpp".to_string(),
+            toolchain(
+                "cpp",
+                Some(&["c++", "-o", "{binary}", "{source}"]),
+                &["{binary}"],
+            ),
+        ),
+        (
+            "python".to_string(),
+            toolchain("py", None, &["python3", "{source}"]),
+        ),
+    ])
+}
+
+impl Toolchain {
+    fn args(args: &[String], source: &Path, binary: &Path) -> Vec<String> {
+        args.iter()
+            .map(|a| {
+                a.replace("{source}", &source.to_string_lossy())
+                    .replace("{binary}", &binary.to_string_lossy())
+            })
+            .collect()
+    }
+
+    // the compiler also runs on generated code, so it gets the environment and
+    // output limit of the program, but its own time and no CPU or memory limit
+    fn compiler(&self) -> Sandbox {
+        Sandbox {
+            timeout_secs: self.timeout_secs,
+            cpu_secs: None,
+            memory_mb: None,
+            ..self.sandbox.clone()
+        }
+    }
+
+    /// Writes `code` into `dir` as main.<extension> and compiles it.
+    pub async fn build(&self, dir: &Path, code: &str) -> Result<Build, io::Error> {
+        tokio::fs::create_dir_all(dir).await?;
+        // the program runs in another directory
+        let dir = tokio::fs::canonicalize(dir).await?;
+        let source = dir.join(format!("main.{}", &self.extension));
+        let binary = dir.join("main");
+        tokio::fs::write(&source, code).await?;
+        let (success, output) = match &self.compile {
+            Some(args) => {
+                let program = Toolchain::args(args, &source, &binary);
+                let execution = execute_in(&dir, &program, "", &self.compiler()).await?;
+                let output = if execution.timed_out || execution.truncated {
+                    execution.to_text()
+                } else {
+                    format!("{}{}", &execution.stdout, &execution.stderr)
+                };
+                (execution.success(), output)
+            }
+            None => (true, String::new()),
+        };
+        Ok(Build {
+            source,
+            binary,
+            success,
+            output,
+        })
+    }
+
+    /// Runs the program of `build` with `stdin` in the sandbox.
+    pub async fn run(&self, build: &Build, stdin: &str) -> Result<Execution, io::Error> {
+        let program = Toolchain::args(&self.run, &build.source, &build.binary);
+        execute(&program, stdin, &self.sandbox).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_and_run() {
+        let shell = toolchain("sh", Some(&["sh", "-n", "{source}"]), &["sh", "{source}"]);
+        let dir = std::env::temp_dir().join(format!("assistant-compile-{}", std::process::id()));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let build = rt.block_on(shell.build(&dir, "read x; echo $x$x")).unwrap();
+        assert!(build.success);
+        assert_eq!(build.source, dir.join("main.sh"));
+        let execution = rt.block_on(shell.run(&build, "ab\n")).unwrap();
+        assert_eq!(execution.stdout, "abab\n");
+
+        let build = rt.block_on(shell.build(&dir, "if then")).unwrap();
+        assert!(!build.success);
+        assert!(!build.output.is_empty());
 
-pub async fn compile(source_path: PathBuf) -> Result<Output, std::io::Error> {
-    let output = if cfg!(target_os = "windows") {
-        Command::new("fsharpc").arg(&source_path).output()
-    } else {
-        Command::new("fsharpc").arg(&source_path).output()
-    };
+        let build = rt.block_on(shell.build(&dir, "sleep 5")).unwrap();
+        let slow = Toolchain {
+            sandbox: Sandbox {
+                timeout_secs: 0,
+                ..Sandbox::default()
+            },
+            ..shell
+        };
+        let execution = rt.block_on(slow.run(&build, "")).unwrap();
+        assert!(execution.timed_out);
 
-    output.await
+        // the compiler runs in the sandbox too, with its own time
+        let home = toolchain("sh", Some(&["sh", "-c", "echo $HOME"]), &["true"]);
+        let build = rt.block_on(home.build(&dir, "")).unwrap();
+        assert_eq!(
+            build.output.trim(),
+            build.source.parent().unwrap().to_str().unwrap()
+        );
+        let hanging = Toolchain {
+            timeout_secs: 0,
+            ..toolchain("sh", Some(&["sleep", "5"]), &["true"])
+        };
+        let build = rt.block_on(hanging.build(&dir, "")).unwrap();
+        assert!(!build.success);
+        assert!(
+            build.output.ends_with("--- timed out\n"),
+            "{}",
+            build.output
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -37,7 +37,6 @@ pub fn convert<T: for<'a> Deserialize<'a> + Clone + Debug>(
         None => {
             let config = serde_yaml::from_str(yaml_string);
             debug!("{:?}", &config);
-            println!("{:?}", &config);
             config.map_err(|_| ConversionFailed)
         }
         Some(key) => {
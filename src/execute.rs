@@ -0,0 +1,295 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+/// Limits of a generated program. Limits set to null are not applied.
+/// Memory and CPU limits are only applied on unix.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Sandbox {
+    // wall clock
+    pub timeout_secs: u64,
+    pub cpu_secs: Option<u64>,
+    // address space
+    pub memory_mb: Option<u64>,
+    // each of stdout and stderr, the rest is discarded
+    pub max_output_bytes: usize,
+    // the only environment variables passed on, so that secrets such as
+    // the API token are not inherited
+    pub env: Vec<String>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            timeout_secs: 10,
+            cpu_secs: Some(10),
+            memory_mb: Some(512),
+            max_output_bytes: 64 * 1024,
+            env: vec!["PATH".to_string(), "LANG".to_string()],
+        }
+    }
+}
+
+impl Sandbox {
+    /// The variables of `vars` listed in `env`, which the program gets.
+    fn passed(&self, vars: impl IntoIterator<Item = (String, String)>) -> Vec<(String, String)> {
+        vars.into_iter()
+            .filter(|(k, _)| self.env.contains(k))
+            .collect()
+    }
+}
+
+/// How a program run in the sandbox ended.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Execution {
+    // None when killed by a signal
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: String,
+    pub stderr: String,
+    // stdout or stderr was cut at max_output_bytes
+    pub truncated: bool,
+}
+
+impl Execution {
+    pub fn success(&self) -> bool {
+        self.code == Some(0) && !self.timed_out
+    }
+
+    pub fn to_text(&self) -> String {
+        let status = match (self.timed_out, self.code, self.signal) {
+            (true, _, _) => "timed out".to_string(),
+            (_, Some(code), _) => format!("exit code {}", code),
+            (_, None, Some(signal)) => format!("killed by signal {}", signal),
+            (_, None, None) => "killed".to_string(),
+        };
+        let truncated = if self.truncated {
+            ", output truncated"
+        } else {
+            ""
+        };
+        format!(
+            "{}{}--- {}{}\n",
+            &self.stdout, &self.stderr, status, truncated
+        )
+    }
+}
+
+#[cfg(unix)]
+fn signal(status: &ExitStatus) -> Option<i32> {
+    std::os::unix::process::ExitStatusExt::signal(status)
+}
+
+#[cfg(not(unix))]
+fn signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+#[cfg(unix)]
+fn limit(command: &mut Command, sandbox: &Sandbox) {
+    let cpu = sandbox.cpu_secs;
+    let memory = sandbox.memory_mb.map(|mb| mb * 1024 * 1024);
+    let set = |resource, value: u64| {
+        let rlimit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    // its own process group, so that everything it starts is killed on timeout
+    command.process_group(0);
+    // SAFETY: only setrlimit, which is async-signal-safe, runs in the child
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(cpu) = cpu {
+                set(libc::RLIMIT_CPU, cpu)?;
+            }
+            if let Some(memory) = memory {
+                set(libc::RLIMIT_AS, memory)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn limit(_command: &mut Command, _sandbox: &Sandbox) {}
+
+#[cfg(unix)]
+fn kill_group(pid: Option<u32>) {
+    if let Some(pid) = pid {
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(_pid: Option<u32>) {}
+
+/// Reads all of `reader`, keeping the first `max` bytes.
+async fn read_capped<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    max: usize,
+) -> Result<(String, bool), io::Error> {
+    let mut kept = Vec::new();
+    let mut truncated = false;
+    if let Some(mut reader) = reader {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let room = max.saturating_sub(kept.len());
+            kept.extend_from_slice(&buf[..n.min(room)]);
+            truncated |= n > room;
+        }
+    }
+    Ok((String::from_utf8_lossy(&kept).to_string(), truncated))
+}
+
+/// Runs `program` with `stdin` in a temporary working directory within the
+/// limits of `sandbox`.
+pub async fn execute(
+    program: &[String],
+    stdin: &str,
+    sandbox: &Sandbox,
+) -> Result<Execution, io::Error> {
+    let dir = tempfile::Builder::new().prefix("assistant-run").tempdir()?;
+    execute_in(dir.path(), program, stdin, sandbox).await
+}
+
+/// Same as `execute` with `dir` as the working and the home directory.
+pub async fn execute_in(
+    dir: &Path,
+    program: &[String],
+    stdin: &str,
+    sandbox: &Sandbox,
+) -> Result<Execution, io::Error> {
+    let (path, args) = program
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+    let mut command = Command::new(path);
+    command
+        .args(args)
+        .current_dir(dir)
+        .env_clear()
+        .envs(sandbox.passed(std::env::vars()))
+        .env("HOME", dir)
+        .env("TMPDIR", dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    limit(&mut command, sandbox);
+    let mut child = command.spawn()?;
+    let pid = child.id();
+
+    let mut input = child.stdin.take();
+    let write = async {
+        if let Some(input) = input.as_mut() {
+            // a program which does not read its input may exit first
+            match input.write_all(stdin.as_bytes()).await {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+                _ => (),
+            }
+        }
+        // closes stdin
+        drop(input);
+        Ok(())
+    };
+    let max = sandbox.max_output_bytes;
+    let run = async {
+        let (written, stdout, stderr) = tokio::join!(
+            write,
+            read_capped(child.stdout.take(), max),
+            read_capped(child.stderr.take(), max)
+        );
+        written?;
+        Ok::<_, io::Error>((stdout?, stderr?, child.wait().await?))
+    };
+    tokio::pin!(run);
+    let timeout = tokio::time::sleep(Duration::from_secs(sandbox.timeout_secs));
+    let (result, timed_out) = tokio::select! {
+        result = &mut run => (result, false),
+        _ = timeout => {
+            kill_group(pid);
+            // a process which left the group may keep the pipes open
+            let rest = tokio::time::timeout(Duration::from_secs(1), &mut run).await;
+            let result = rest.unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            });
+            (result, true)
+        }
+    };
+    let ((stdout, out_truncated), (stderr, err_truncated), status) = result?;
+    Ok(Execution {
+        code: status.code(),
+        signal: signal(&status),
+        timed_out,
+        stdout,
+        stderr,
+        truncated: out_truncated || err_truncated,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".to_string(), "-c".to_string(), script.to_string()]
+    }
+
+    #[test]
+    fn test_execute() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let sandbox = Sandbox::default();
+
+        let execution = rt
+            .block_on(execute(&sh("read x; echo $x; exit 3"), "abc\n", &sandbox))
+            .unwrap();
+        assert_eq!(execution.stdout, "abc\n");
+        assert_eq!(execution.code, Some(3));
+        assert!(!execution.success());
+
+        // nothing but the listed variables is inherited
+        let vars = [("PATH", "/bin"), ("OPENAI_API_KEY", "secret")]
+            .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert_eq!(
+            sandbox.passed(vars),
+            vec![("PATH".to_string(), "/bin".to_string())]
+        );
+        let execution = rt.block_on(execute(&sh("pwd"), "", &sandbox)).unwrap();
+        assert!(execution.stdout.contains("assistant-run"));
+
+        let small = Sandbox {
+            max_output_bytes: 4,
+            ..Sandbox::default()
+        };
+        let execution = rt
+            .block_on(execute(&sh("echo 0123456789"), "", &small))
+            .unwrap();
+        assert_eq!(execution.stdout, "0123");
+        assert!(execution.truncated);
+
+        let quick = Sandbox {
+            timeout_secs: 0,
+            ..Sandbox::default()
+        };
+        let execution = rt.block_on(execute(&sh("sleep 5"), "", &quick)).unwrap();
+        assert!(execution.timed_out);
+        assert!(!execution.success());
+    }
+}
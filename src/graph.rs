@@ -0,0 +1,420 @@
+use crate::scenario::{find_start_items, Guard, Renderer, StateTrans, Target, Workflow};
+use crate::Talk;
+use clap::ValueEnum;
+use std::collections::HashSet;
+use std::fmt::Debug;
+
+type Tag = String;
+type Name = String;
+type Node = (Name, Tag);
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+// None is the end of the workflow where a conditional transition stops
+#[derive(Clone, Debug, PartialEq)]
+struct Edge {
+    from: Node,
+    to: Option<Node>,
+    label: String,
+}
+
+fn guard_label(guard: &Guard) -> String {
+    match guard {
+        Guard::Matches(pattern) => format!("/{}/", pattern),
+        Guard::Json { pointer, equals } => format!("{} == {}", pointer, equals),
+        Guard::Built(true) => "built".to_string(),
+        Guard::Built(false) => "build failed".to_string(),
+        Guard::Passed(true) => "passed".to_string(),
+        Guard::Passed(false) => "failed".to_string(),
+        Guard::Iterations(n) => format!("{} times", n),
+        Guard::Not { guard } => format!("not {}", guard_label(guard)),
+    }
+}
+
+fn edges(from: &Node, next: &StateTrans) -> Vec<Edge> {
+    let edge = |to: Option<&Target>, label: &str| Edge {
+        from: from.clone(),
+        to: to.map(|t| (t.name.clone(), t.tag.clone())),
+        label: label.to_string(),
+    };
+    let to = |name: &Name, tag: &Tag, label: String| Edge {
+        from: from.clone(),
+        to: Some((name.clone(), tag.clone())),
+        label,
+    };
+    match next {
+        StateTrans::Stop => vec![],
+        StateTrans::Next { auto, name, tag } => vec![to(
+            name,
+            tag,
+            auto.map(|k| format!("auto {}", k)).unwrap_or_default(),
+        )],
+        StateTrans::Wait { name, tag } => vec![to(name, tag, String::new())],
+        StateTrans::Repair { retries, name, tag } => vec![
+            to(name, tag, "built".to_string()),
+            to(&from.0, &from.1, format!("retry {}", retries)),
+        ],
+        StateTrans::Test { passed, failed } => vec![
+            edge(passed.as_ref(), "passed"),
+            edge(failed.as_ref(), "failed"),
+        ],
+        StateTrans::Branch {
+            edges: branches,
+            default,
+        } => branches
+            .iter()
+            .map(|e| edge(e.to.as_ref(), &guard_label(&e.when)))
+            .chain([edge(default.as_ref(), "default")])
+            .collect(),
+        StateTrans::FanOut { to: branches, join } => branches
+            .iter()
+            .flat_map(|b| {
+                let branch = (b.name.clone(), b.tag.clone());
+                [
+                    edge(Some(b), "fan out"),
+                    Edge {
+                        from: branch,
+                        to: Some((join.name.clone(), join.tag.clone())),
+                        label: "join".to_string(),
+                    },
+                ]
+            })
+            .collect(),
+    }
+}
+
+/// Items answered in a conversation and the transitions taken between them.
+#[derive(Debug, Default)]
+pub struct Trace {
+    visited: HashSet<Node>,
+    taken: HashSet<(Node, Node)>,
+}
+
+impl Trace {
+    pub fn new(talks: &[Talk]) -> Trace {
+        let mut visited = HashSet::new();
+        let mut steps: Vec<(Node, Node)> = vec![];
+        let mut last: Option<Node> = None;
+        for talk in talks {
+            match talk {
+                Talk::FromAi { name, tag, .. } => {
+                    let node = (name.clone(), tag.clone());
+                    if let Some(last) = last.replace(node.clone()) {
+                        steps.push((last, node.clone()));
+                    }
+                    visited.insert(node);
+                }
+                // the items of a fan-out answered one after the other, but each
+                // of them went from the item before them to the join
+                Talk::Joined { name, tag, from } => {
+                    let first = steps.len().saturating_sub(from.len());
+                    let fan_out = steps.get(first).map(|(f, _)| f.clone());
+                    steps.truncate(first);
+                    let join = (name.clone(), tag.clone());
+                    for branch in from {
+                        if let Some(fan_out) = &fan_out {
+                            steps.push((fan_out.clone(), branch.clone()));
+                        }
+                        steps.push((branch.clone(), join.clone()));
+                    }
+                    last = None;
+                }
+                _ => (),
+            }
+        }
+        Trace {
+            visited,
+            taken: steps.into_iter().collect(),
+        }
+    }
+}
+
+/// Items, transitions and the path taken through them.
+struct Graph {
+    nodes: Vec<Node>,
+    starts: HashSet<Node>,
+    waits: HashSet<Node>,
+    edges: Vec<Edge>,
+    visited: HashSet<Node>,
+    taken: HashSet<(Node, Node)>,
+}
+
+impl Graph {
+    fn new<S, T, I, O>(wf: &Workflow<S, T, I, O>, trace: &Trace) -> Graph
+    where
+        S: Debug,
+        T: Debug,
+        I: Renderer<S, T> + Clone + Debug,
+        O: Renderer<S, T> + Clone + Debug,
+    {
+        let mut nodes: Vec<Node> = wf
+            .iter()
+            .flat_map(|(n, hm)| hm.keys().map(move |t| (n.clone(), t.clone())))
+            .collect();
+        nodes.sort();
+        let next = |node: &Node| &wf[&node.0][&node.1].next;
+        let waits = nodes
+            .iter()
+            .filter(|node| matches!(next(node), StateTrans::Wait { .. }))
+            .cloned()
+            .collect();
+        let mut edges: Vec<Edge> = nodes.iter().flat_map(|n| edges(n, next(n))).collect();
+        // jumps which no transition explains, e.g. made from the GUI
+        let mut jumps: Vec<&(Node, Node)> = trace
+            .taken
+            .iter()
+            .filter(|(from, to)| {
+                !edges
+                    .iter()
+                    .any(|e| &e.from == from && e.to.as_ref() == Some(to))
+            })
+            .collect();
+        jumps.sort();
+        edges.extend(jumps.into_iter().map(|(from, to)| Edge {
+            from: from.clone(),
+            to: Some(to.clone()),
+            label: "jump".to_string(),
+        }));
+        Graph {
+            starts: find_start_items(wf).into_iter().collect(),
+            waits,
+            edges,
+            visited: trace.visited.clone(),
+            taken: trace.taken.clone(),
+            nodes,
+        }
+    }
+
+    fn label(&self, node: &Node) -> String {
+        if self.waits.contains(node) {
+            format!("({}, {})\nwait", node.0, node.1)
+        } else {
+            format!("({}, {})", node.0, node.1)
+        }
+    }
+
+    fn is_taken(&self, edge: &Edge) -> bool {
+        edge.to
+            .as_ref()
+            .is_some_and(|to| self.taken.contains(&(edge.from.clone(), to.clone())))
+    }
+
+    fn has_stop(&self) -> bool {
+        self.edges.iter().any(|e| e.to.is_none())
+    }
+
+    fn to_dot(&self) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let id = |node: &Node| format!("\"{}.{}\"", escape(&node.0), escape(&node.1));
+        let mut lines = vec!["digraph workflow {".to_string()];
+        for node in &self.nodes {
+            let mut attrs = vec![format!(
+                "label=\"{}\"",
+                escape(&self.label(node)).replace('\n', "\\n")
+            )];
+            if self.starts.contains(node) {
+                attrs.push("peripheries=2".to_string());
+            }
+            if self.visited.contains(node) {
+                attrs.push("style=filled, fillcolor=lightblue".to_string());
+            }
+            lines.push(format!("  {} [{}];", id(node), attrs.join(", ")));
+        }
+        if self.has_stop() {
+            lines.push("  stop [shape=point];".to_string());
+        }
+        for edge in &self.edges {
+            let mut attrs = vec![];
+            if !edge.label.is_empty() {
+                attrs.push(format!("label=\"{}\"", escape(&edge.label)));
+            }
+            if self.is_taken(edge) {
+                attrs.push("color=red, penwidth=2".to_string());
+            }
+            if edge.label == "jump" {
+                attrs.push("style=dashed".to_string());
+            }
+            let to = edge.to.as_ref().map_or("stop".to_string(), id);
+            let attrs = if attrs.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", attrs.join(", "))
+            };
+            lines.push(format!("  {} -> {}{};", id(&edge.from), to, attrs));
+        }
+        lines.push("}".to_string());
+        lines.join("\n") + "\n"
+    }
+
+    fn to_mermaid(&self) -> String {
+        // mermaid ids are plain words, and quotes end a label
+        let id = |node: &Node| {
+            self.nodes
+                .iter()
+                .position(|n| n == node)
+                .map_or(format!("{}_{}", node.0, node.1), |i| format!("n{}", i))
+        };
+        let escape = |s: &str| s.replace('"', "#quot;").replace('|', "#124;");
+        let mut lines = vec!["flowchart TD".to_string()];
+        for node in &self.nodes {
+            let label = escape(&self.label(node)).replace('\n', "<br/>");
+            lines.push(format!("  {}[\"{}\"]", id(node), label));
+        }
+        if self.has_stop() {
+            lines.push("  stop((stop))".to_string());
+        }
+        let mut taken = vec![];
+        for (i, edge) in self.edges.iter().enumerate() {
+            let arrow = if edge.label == "jump" { "-.->" } else { "-->" };
+            let label = if edge.label.is_empty() {
+                String::new()
+            } else {
+                format!("|\"{}\"|", escape(&edge.label))
+            };
+            let to = edge.to.as_ref().map_or("stop".to_string(), id);
+            lines.push(format!("  {} {}{} {}", id(&edge.from), arrow, label, to));
+            if self.is_taken(edge) {
+                taken.push(i.to_string());
+            }
+        }
+        lines.push("  classDef start stroke-width:4px".to_string());
+        lines.push("  classDef visited fill:#add8e6".to_string());
+        for (class, nodes) in [("start", &self.starts), ("visited", &self.visited)] {
+            let mut ids: Vec<String> = self
+                .nodes
+                .iter()
+                .filter(|n| nodes.contains(n))
+                .map(id)
+                .collect();
+            ids.sort();
+            if !ids.is_empty() {
+                lines.push(format!("  class {} {}", ids.join(","), class));
+            }
+        }
+        if !taken.is_empty() {
+            lines.push(format!(
+                "  linkStyle {} stroke:red,stroke-width:2px",
+                taken.join(",")
+            ));
+        }
+        lines.join("\n") + "\n"
+    }
+}
+
+/// The workflow as a diagram. What `trace` went through is highlighted.
+pub fn render<S, T, I, O>(wf: &Workflow<S, T, I, O>, format: GraphFormat, trace: &Trace) -> String
+where
+    S: Debug,
+    T: Debug,
+    I: Renderer<S, T> + Clone + Debug,
+    O: Renderer<S, T> + Clone + Debug,
+{
+    let graph = Graph::new(wf, trace);
+    match format {
+        GraphFormat::Dot => graph.to_dot(),
+        GraphFormat::Mermaid => graph.to_mermaid(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::read_config;
+    use crate::{Content, Rendered, RenderingContext, Request, Response};
+
+    fn answered(name: &str, tag: &str) -> Talk {
+        Talk::FromAi {
+            name: name.to_string(),
+            tag: tag.to_string(),
+            message: Content::Text(String::new()),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let workflow_str = r#"
+  king:
+    k1:
+      start: true
+      next: !Wait
+        name: king
+        tag: k2
+      request:
+        path: req
+      response:
+        path: rsp
+    k2:
+      next: !Branch
+        edges:
+          - when: !Matches "DONE"
+          - when: !Not
+              guard: !Iterations 3
+            to:
+              name: king
+              tag: k1
+      request:
+        path: req
+      response:
+        path: rsp
+        "#;
+        let wf: Workflow<RenderingContext, Rendered, Request, Response> =
+            read_config(None, workflow_str).unwrap();
+        let talks = [
+            answered("king", "k1"),
+            answered("king", "k2"),
+            answered("king", "k2"),
+        ];
+        assert_eq!(
+            render(&wf, GraphFormat::Dot, &Trace::new(&talks)),
+            r#"digraph workflow {
+  "king.k1" [label="(king, k1)\nwait", peripheries=2, style=filled, fillcolor=lightblue];
+  "king.k2" [label="(king, k2)", style=filled, fillcolor=lightblue];
+  stop [shape=point];
+  "king.k1" -> "king.k2" [color=red, penwidth=2];
+  "king.k2" -> stop [label="/DONE/"];
+  "king.k2" -> "king.k1" [label="not 3 times"];
+  "king.k2" -> stop [label="default"];
+  "king.k2" -> "king.k2" [label="jump", color=red, penwidth=2, style=dashed];
+}
+"#
+        );
+        let mermaid = render(&wf, GraphFormat::Mermaid, &Trace::default());
+        assert!(mermaid.contains("  n0[\"(king, k1)<br/>wait\"]\n"));
+        assert!(mermaid.contains("  n1 -->|\"default\"| stop\n"));
+        assert!(mermaid.contains("  class n0 start\n"));
+        assert!(!mermaid.contains("linkStyle"));
+    }
+
+    #[test]
+    fn test_trace_fan_out() {
+        let s = |n: &str, t: &str| (n.to_string(), t.to_string());
+        let talks = [
+            answered("coder", "c1"),
+            answered("reviewer", "r1"),
+            answered("tester", "t1"),
+            Talk::Joined {
+                name: "judge".to_string(),
+                tag: "j1".to_string(),
+                from: vec![s("reviewer", "r1"), s("tester", "t1")],
+            },
+            answered("judge", "j1"),
+        ];
+        let trace = Trace::new(&talks);
+        let mut taken: Vec<_> = trace.taken.into_iter().collect();
+        taken.sort();
+        assert_eq!(
+            taken,
+            vec![
+                (s("coder", "c1"), s("reviewer", "r1")),
+                (s("coder", "c1"), s("tester", "t1")),
+                (s("reviewer", "r1"), s("judge", "j1")),
+                (s("tester", "t1"), s("judge", "j1")),
+            ]
+        );
+        assert_eq!(trace.visited.len(), 4);
+    }
+}
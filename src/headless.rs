@@ -0,0 +1,401 @@
+use crate::compile::Toolchains;
+use crate::openai_api::{ask, ask_all, connect, Backends, Cancel, Session};
+use crate::save_and_compile;
+use crate::scenario::TestCase;
+use crate::scenario::{get_item, Prompt, Renderer, StateTrans, Workflow};
+use crate::sessions::{write_session, write_sessions, SavedSession};
+use crate::{answer_talks, build_dir, build_talks, code_to_build, next_by_answer};
+use crate::{check_json, fan_out_queries, joined_talks, JsonCheck};
+use crate::{counters, item_markers, processed_talks};
+use crate::{AssistantError, Content, Request, Response};
+use crate::{AssistantName, Rendered, RenderingContext};
+use crate::{Tag, Talk, Templates};
+use log::{error, info, warn};
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Where the edited input of a `Wait` state comes from when no GUI is available.
+#[derive(Clone, Debug)]
+pub enum WaitInput {
+    /// Read lines from stdin until a line containing only "." or EOF.
+    Stdin,
+    /// Read `<dir>/<name>.<tag>.txt`. The rendered input is used when the file is missing.
+    Dir(PathBuf),
+}
+
+impl WaitInput {
+    fn read(&self, name: &str, tag: &str, rendered: &str) -> Result<String, AssistantError> {
+        match self {
+            WaitInput::Stdin => {
+                eprintln!(
+                    "--- Input for ({}, {}), end with a line containing only '.'",
+                    name, tag
+                );
+                eprintln!("{}", rendered);
+                io::stderr().flush()?;
+                let mut lines = Vec::new();
+                for line in io::stdin().lock().lines() {
+                    let line = line?;
+                    if line == "." {
+                        break;
+                    }
+                    lines.push(line);
+                }
+                if lines.is_empty() {
+                    Ok(rendered.to_string())
+                } else {
+                    Ok(lines.join("\n"))
+                }
+            }
+            WaitInput::Dir(dir) => {
+                let path = dir.join(format!("{}.{}.txt", name, tag));
+                if path.exists() {
+                    Ok(fs::read_to_string(path)?)
+                } else {
+                    warn!("{:?} not found, using rendered input", path);
+                    Ok(rendered.to_string())
+                }
+            }
+        }
+    }
+}
+
+// Unlike the GUI, nobody is there to press "Ask AI", so Next is always followed.
+// A counted Next stops being followed once its counter reaches zero.
+fn next_headless<'a>(
+    wf: &mut Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    name: &AssistantName,
+    tag: &Tag,
+) -> Option<(AssistantName, Tag)> {
+    let item = wf.get_mut(name).and_then(|hm| hm.get_mut(tag))?;
+    match &mut item.next {
+        StateTrans::Stop => None,
+        StateTrans::Next { auto: Some(0), .. } => None,
+        StateTrans::Next {
+            auto: Some(k),
+            name,
+            tag,
+        } => {
+            *k -= 1;
+            Some((name.clone(), tag.clone()))
+        }
+        StateTrans::Next {
+            auto: None,
+            name,
+            tag,
+        } => Some((name.clone(), tag.clone())),
+        StateTrans::Wait { name, tag } => Some((name.clone(), tag.clone())),
+        StateTrans::FanOut { join, .. } => Some((join.name.clone(), join.tag.clone())),
+        // decided by next_by_answer
+        StateTrans::Repair { .. } | StateTrans::Test { .. } | StateTrans::Branch { .. } => None,
+    }
+}
+
+/// Compiles the code in the last answer of `(name, tag)` and runs `tests` on
+/// it, or the program with no input if there is no test, as the GUI does.
+/// An answer which cannot be built is a failed build, so that the assistant is
+/// asked again.
+async fn check_answer(
+    toolchains: &Toolchains,
+    output_dir: &str,
+    conversations: &[Talk],
+    name: &AssistantName,
+    tag: &Tag,
+    tests: &[TestCase],
+) -> Result<Vec<Talk>, AssistantError> {
+    let (lang, toolchain, code) = match code_to_build(toolchains, conversations, name, tag) {
+        Ok(found) => found,
+        Err((lang, reason)) => {
+            return Ok(vec![Talk::Build {
+                name: name.clone(),
+                tag: tag.clone(),
+                lang,
+                success: false,
+                output: reason,
+            }])
+        }
+    };
+    let (build, execution, report) = save_and_compile(
+        toolchain.clone(),
+        build_dir(output_dir, name, tag),
+        code,
+        tests.to_vec(),
+    )
+    .await?;
+    info!("Build ({}, {}): {}", name, tag, build.success);
+    if let Some(report) = &report {
+        info!("Tests ({}, {}): {}", name, tag, report.summary());
+    }
+    Ok(build_talks(name, tag, lang, (build, execution, report)))
+}
+
+fn save_conversation(output_dir: &str, conversations: &Vec<Talk>) -> Result<(), AssistantError> {
+    let output_dir_path = PathBuf::from(output_dir);
+    fs::create_dir_all(&output_dir_path)?;
+    let s = serde_yaml::to_string(conversations)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(output_dir_path.join("conversation.yaml"), s)?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run_workflow<'a>(
+    backends: Backends,
+    default_key: String,
+    prompts: HashMap<String, Box<Prompt>>,
+    mut workflow: Workflow<RenderingContext<'a>, Rendered, Request, Response>,
+    handlebars: Templates<'a>,
+    start: Option<(AssistantName, Tag)>,
+    mut conversations: Vec<Talk>,
+    saved: Vec<Session>,
+    toolchains: Toolchains,
+    markers: Vec<Regex>,
+    session: Option<PathBuf>,
+    output_dir: String,
+    wait_input: WaitInput,
+    max_steps: usize,
+) -> Result<Vec<Talk>, AssistantError> {
+    let names = prompts.keys().cloned().collect::<Vec<_>>();
+    let context = connect(backends, default_key, names, prompts.clone(), saved).await?;
+    context.restore_history(&conversations);
+    write_sessions(&output_dir, &context.sessions())?;
+    let context = Arc::new(Mutex::new(context));
+
+    let mut current = start;
+    let mut steps = 0;
+    // steps may fail midway. The session is written anyway, at the item which failed.
+    let run = async {
+        while let Some((name, tag)) = current.clone() {
+            if steps >= max_steps {
+                warn!("max steps {} reached at ({}, {})", max_steps, &name, &tag);
+                break;
+            }
+            steps += 1;
+            info!("Run: ({:?}, {:?})", &name, &tag);
+            let item = get_item(&workflow, &name, &tag);
+            let input = prompts.get(&name).and_then(|p| {
+                p.inputs
+                    .get(&tag)
+                    .map(|i| (p.instruction.clone(), i.clone()))
+            });
+            let (item, (instruction, input)) = match (item, input) {
+                (Some(item), Some(input)) => (item, input),
+                _ => {
+                    error!("({}, {}) is not defined", &name, &tag);
+                    return Err(AssistantError::Workflow(format!(
+                        "({}, {}) is not defined",
+                        &name, &tag
+                    )));
+                }
+            };
+
+            let rendered = item.request.render((
+                &handlebars,
+                &conversations,
+                &instruction,
+                &input,
+                (&name, &tag),
+            ))?;
+            conversations.push(Talk::ToAi {
+                name: name.clone(),
+                tag: tag.clone(),
+                message: Content::Text(rendered.clone()),
+            });
+            let query = match item.next {
+                StateTrans::Wait { .. } => wait_input.read(&name, &tag, &rendered)?,
+                _ => rendered,
+            };
+            conversations.push(Talk::ToAi {
+                name: name.clone(),
+                tag: tag.clone(),
+                message: Content::Text(query.clone()),
+            });
+
+            let (name, tag, answer) = ask(context.clone(), name, tag, query, Cancel::default())
+                .await
+                .map_err(|(_, e)| e)?;
+            conversations.extend(answer_talks(&name, &tag, answer));
+            while let Some(check) = check_json(&mut workflow, &name, &tag, &conversations) {
+                match check {
+                    JsonCheck::Valid(talk) => {
+                        conversations.push(talk);
+                        break;
+                    }
+                    JsonCheck::Retry(query) => {
+                        info!("({:?}, {:?}) does not follow the schema", &name, &tag);
+                        conversations.push(Talk::ToAi {
+                            name: name.clone(),
+                            tag: tag.clone(),
+                            message: Content::Text(query.clone()),
+                        });
+                        let (_, _, answer) = ask(
+                            context.clone(),
+                            name.clone(),
+                            tag.clone(),
+                            query,
+                            Cancel::default(),
+                        )
+                        .await
+                        .map_err(|(_, e)| e)?;
+                        conversations.extend(answer_talks(&name, &tag, answer));
+                    }
+                    JsonCheck::Invalid(errors) => {
+                        save_conversation(&output_dir, &conversations)?;
+                        return Err(AssistantError::InvalidJson(format!(
+                            "({}, {})\n{}",
+                            &name, &tag, errors
+                        )));
+                    }
+                }
+            }
+
+            let item_markers = item_markers(&item, &markers);
+            conversations.extend(processed_talks(&conversations, &name, &tag, &item_markers));
+
+            if item.next.needs_build() {
+                let talks = check_answer(
+                    &toolchains,
+                    &output_dir,
+                    &conversations,
+                    &name,
+                    &tag,
+                    &input.tests,
+                )
+                .await?;
+                conversations.extend(talks);
+            }
+            // after the build, so that the response can show how it went
+            let response_text = item.response.render((
+                &handlebars,
+                &conversations,
+                &instruction,
+                &input,
+                (&name, &tag),
+            ))?;
+            println!("{}", response_text);
+
+            if let StateTrans::FanOut { to, join } = &item.next {
+                info!("Fan out: ({:?}, {:?}) to {:?}", &name, &tag, to);
+                let queries =
+                    fan_out_queries(&handlebars, &workflow, &prompts, &mut conversations, to)?;
+                let answers = match ask_all(context.clone(), queries, Cancel::default()).await {
+                    Ok(answers) => answers,
+                    Err((_, e, answered)) => {
+                        // kept in the session, the fan-out is asked again as a whole
+                        for (name, tag, answer) in answered {
+                            conversations.extend(answer_talks(&name, &tag, answer));
+                        }
+                        return Err(e.into());
+                    }
+                };
+                conversations.extend(joined_talks(answers, join));
+                // each response as if its item had been asked alone
+                for target in to {
+                    let answered = conversations.iter().rposition(|talk| {
+                        matches!(talk, Talk::FromAi { name, tag, .. }
+                            if (name, tag) == (&target.name, &target.tag))
+                    });
+                    if let (Some(item), Some(prompt), Some(answered)) = (
+                        get_item(&workflow, &target.name, &target.tag),
+                        prompts.get(&target.name),
+                        answered,
+                    ) {
+                        let input = &prompt.inputs[&target.tag];
+                        let response_text = item.response.render((
+                            &handlebars,
+                            &conversations[..=answered],
+                            &prompt.instruction,
+                            input,
+                            (&target.name, &target.tag),
+                        ))?;
+                        println!("{}", response_text);
+                    }
+                }
+            }
+            // only once the item is done, so that a failed one is where the session stops
+            current = match item.next {
+                StateTrans::Repair { .. } | StateTrans::Test { .. } | StateTrans::Branch { .. } => {
+                    next_by_answer(&mut workflow, &name, &tag, &conversations)
+                }
+                _ => next_headless(&mut workflow, &name, &tag),
+            };
+        }
+        Ok::<(), AssistantError>(())
+    }
+    .await;
+
+    if let Some(path) = session {
+        // where max_steps or an error stopped the workflow, if they did
+        let saved = SavedSession {
+            conversations: conversations.clone(),
+            current,
+            counters: counters(&workflow),
+            sessions: context.lock().await.sessions(),
+            ..SavedSession::default()
+        };
+        write_session(&path, &saved)?;
+    }
+    run?;
+    save_conversation(&output_dir, &conversations)?;
+    Ok(conversations)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::read_config;
+
+    #[test]
+    fn test_next_headless() {
+        let workflow_str = r#"
+  king:
+    k1:
+      next: !Next
+        auto: 1
+        name: queen
+        tag: q1
+      request:
+        path: req
+      response:
+        path: rsp
+  queen:
+    q1:
+      next: !Wait
+        name: king
+        tag: k1
+      request:
+        path: req
+      response:
+        path: rsp
+    q2:
+      next: !Stop
+      request:
+        path: req
+      response:
+        path: rsp
+        "#;
+        let mut wf: Workflow<RenderingContext, Rendered, Request, Response> =
+            read_config(None, workflow_str).unwrap();
+        let king = "king".to_string();
+        let k1 = "k1".to_string();
+        assert_eq!(
+            next_headless(&mut wf, &king, &k1),
+            Some(("queen".to_string(), "q1".to_string()))
+        );
+        // auto counter is exhausted
+        assert_eq!(next_headless(&mut wf, &king, &k1), None);
+        assert_eq!(
+            next_headless(&mut wf, &"queen".to_string(), &"q1".to_string()),
+            Some((king.clone(), k1.clone()))
+        );
+        assert_eq!(
+            next_headless(&mut wf, &"queen".to_string(), &"q2".to_string()),
+            None
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use crate::parse_json;
+use handlebars::{handlebars_helper, Handlebars};
+use log::error;
+use regex::Regex;
+use serde_json::Value;
+
+// {{json last_response "/verdict"}}: the value at the JSON pointer, in the
+// answer itself or in its first code block
+handlebars_helper!(json_helper: |text: str, pointer: str| {
+    parse_json(text)
+        .and_then(|v| v.pointer(pointer).cloned())
+        .unwrap_or(Value::Null)
+});
+
+// {{regex last_response "score: ([0-9]+)"}}: the first group, or the whole match
+handlebars_helper!(regex_helper: |text: str, pattern: str| {
+    match Regex::new(pattern) {
+        Ok(re) => re
+            .captures(text)
+            .and_then(|c| c.get(1).or_else(|| c.get(0)))
+            .map_or(String::new(), |m| m.as_str().to_string()),
+        Err(e) => {
+            error!("invalid regex {}: {}", pattern, e);
+            String::new()
+        }
+    }
+});
+
+// {{indent text 4}}: every line but the empty ones moved right
+handlebars_helper!(indent_helper: |text: str, width: u64| {
+    let pad = " ".repeat(width as usize);
+    text.lines()
+        .map(|l| if l.is_empty() { String::new() } else { format!("{}{}", pad, l) })
+        .collect::<Vec<_>>()
+        .join("\n")
+});
+
+handlebars_helper!(trim_helper: |text: str| text.trim());
+
+/// Registers the helpers. Prompts are plain text, so nothing is HTML escaped
+/// either, which would garble code such as backticks and `<`.
+pub fn register_helpers(handlebars: &mut Handlebars) {
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars.register_helper("json", Box::new(json_helper));
+    handlebars.register_helper("regex", Box::new(regex_helper));
+    handlebars.register_helper("indent", Box::new(indent_helper));
+    handlebars.register_helper("trim", Box::new(trim_helper));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_helpers() {
+        let mut hb = Handlebars::new();
+        register_helpers(&mut hb);
+        let data = json!({
+            "answer": "```json\n{\"verdict\": {\"ok\": true, \"score\": 7}}\n```",
+            "text": "  a\n\nb  ",
+        });
+        let render = |template: &str| hb.render_template(template, &data).unwrap();
+        assert_eq!(render(r#"{{json answer "/verdict/score"}}"#), "7");
+        assert_eq!(
+            render(r#"{{#if (json answer "/verdict/ok")}}ok{{/if}}"#),
+            "ok"
+        );
+        assert_eq!(render(r#"{{json answer "/missing"}}"#), "");
+        assert_eq!(render(r#"{{regex answer "score.: ([0-9]+)"}}"#), "7");
+        assert_eq!(render(r#"{{regex answer "verdict"}}"#), "verdict");
+        assert_eq!(render(r#"{{regex answer "("}}"#), "");
+        assert_eq!(render("{{indent text 2}}"), "    a\n\n  b  ");
+        assert_eq!(render("[{{trim text}}]"), "[a\n\nb]");
+        assert_eq!(render("{{answer}}"), data["answer"].as_str().unwrap());
+    }
+}
@@ -1,63 +1,146 @@
-use crate::Prompt;
+use crate::replay::ReplayBackend;
+use crate::scenario::Tool;
+use crate::tools::{call_tool, schema, ToolCall};
+use crate::{Prompt, Talk};
 use async_openai::config::Config;
 use async_openai::{
     config::{AzureConfig, OpenAIConfig},
     error::OpenAIError,
     types::{
-        AssistantObject, CreateAssistantRequestArgs, CreateMessageRequestArgs,
-        CreateRunRequestArgs, CreateThreadRequestArgs, MessageContent, RunStatus, ThreadObject,
+        AssistantObject, AssistantStreamEvent, AssistantTools,
+        ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
+        ChatCompletionRequestSystemMessage, ChatCompletionRequestToolMessage,
+        ChatCompletionRequestUserMessage, ChatCompletionTool, ChatCompletionToolType,
+        CreateAssistantRequestArgs, CreateChatCompletionRequestArgs, CreateMessageRequestArgs,
+        CreateRunRequestArgs, CreateThreadRequestArgs, FunctionObject, MessageContent,
+        MessageDeltaContent, MessageDeltaContentImageFileObject, MessageDeltaContentImageUrlObject,
+        MessageDeltaContentRefusalObject, MessageDeltaContentText, MessageDeltaContentTextObject,
+        ModifyAssistantRequestArgs, RunObject, RunStatus, SubmitToolOutputsRunRequest,
+        ThreadObject, ToolsOutputs,
     },
     Client,
 };
+use async_stream::{stream, try_stream};
+use futures::future;
+use futures::stream::{self, BoxStream, StreamExt};
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::future::Future;
+use std::path::Path;
+use std::pin::{pin, Pin};
+use std::time::Duration;
 
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
 
 use crate::OpenAIApiError::OpenAIAccessError;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use thiserror::Error;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+
+/// Which OpenAI API a service talks to.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum Api {
+    /// Beta Assistants API. Threads and assistants are kept on the server.
+    #[default]
+    Assistants,
+    /// Chat completions. The conversation history is kept locally.
+    ChatCompletions,
+}
+
+/// How long to wait for an answer. Runs of the Assistants API are polled every
+/// `interval_ms`, doubled after each poll up to `max_interval_ms`.
+/// A request not answered within `timeout_secs` is cancelled.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Polling {
+    pub interval_ms: u64,
+    pub max_interval_ms: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Polling {
+            interval_ms: 500,
+            max_interval_ms: 8000,
+            timeout_secs: 600,
+        }
+    }
+}
+
+impl Polling {
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    fn next_interval(&self, current: Duration) -> Duration {
+        (current * 2).min(Duration::from_millis(self.max_interval_ms))
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum OpenAi {
     OpenAiToken {
         token: String,
         model: String,
+        // base url of an OpenAI compatible server
+        #[serde(default)]
+        api_base: Option<String>,
+        #[serde(default)]
+        api: Api,
+        #[serde(default)]
+        polling: Polling,
     },
     AzureAiToken {
         key: String,
         endpoint: String,
         deployment_id: String,
         api_version: String,
+        #[serde(default)]
+        api: Api,
+        #[serde(default)]
+        polling: Polling,
+    },
+    // answers from a recorded conversation (see replay.rs)
+    Replay {
+        fixture: String,
     },
 }
 
 impl Debug for OpenAi {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            OpenAi::OpenAiToken { model, .. } => {
-                write!(f, "OpenAiToken {{ token: **** model: {} }}", model)
+            OpenAi::OpenAiToken {
+                model,
+                api_base,
+                api,
+                ..
+            } => {
+                write!(
+                    f,
+                    "OpenAiToken {{ token: **** model: {}, api_base: {:?}, api: {:?} }}",
+                    model, api_base, api
+                )
             }
             OpenAi::AzureAiToken {
                 endpoint,
                 deployment_id,
                 api_version,
+                api,
                 ..
             } => write!(
                 f,
-                "AzureAiToken {{ key: ****, endpoint: {}, deployment_id: {}, api_version: {} }}",
-                endpoint, deployment_id, api_version
+                "AzureAiToken {{ key: ****, endpoint: {}, deployment_id: {}, api_version: {}, api: {:?} }}",
+                endpoint, deployment_id, api_version, api
             ),
-        }
-    }
-}
-
-impl OpenAi {
-    fn get_model(&self) -> String {
-        match self {
-            OpenAi::OpenAiToken { model, .. } => model.clone(),
-            OpenAi::AzureAiToken { .. } => "".to_string(),
+            OpenAi::Replay { fixture } => write!(f, "Replay {{ fixture: {} }}", fixture),
         }
     }
 }
@@ -67,57 +150,898 @@ impl Default for OpenAi {
         OpenAi::OpenAiToken {
             token: "".to_string(),
             model: "".to_string(),
+            api_base: None,
+            api: Api::default(),
+            polling: Polling::default(),
         }
     }
 }
 
 pub type AssistantName = String;
+pub type RunId = String;
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Non text content of an answer.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Attachment {
+    ImageFile { file_id: String },
+    ImageUrl { url: String },
+}
+
+/// Answer of a run. Text parts are concatenated into `text`.
+/// `tool_calls` are the calls executed before the answer, in order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Answer {
+    pub text: String,
+    pub attachments: Vec<Attachment>,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl From<String> for Answer {
+    fn from(text: String) -> Answer {
+        Answer {
+            text,
+            ..Answer::default()
+        }
+    }
+}
+
+/// Events of a streamed answer. `Completed` carries the whole answer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamEvent {
+    /// The run to cancel if the answer is not awaited any more.
+    RunCreated(RunId),
+    Delta(String),
+    Completed(Answer),
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Shared by the asker and the one waiting for the answer. Once cancelled, the
+/// pending run is cancelled on the server and `ask` returns `RunCancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct Cancel(Arc<CancelState>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    async fn cancelled(&self) {
+        let mut notified = pin!(self.0.notify.notified());
+        notified.as_mut().enable();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Server side objects a backend created for one assistant.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Session {
+    pub name: AssistantName,
+    // config key of the backend which created it
+    #[serde(default)]
+    pub service: String,
+    pub assistant_id: String,
+    pub thread_id: String,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+}
+
+/// A provider which can hold conversations. Backends are chosen at runtime from
+/// `OpenAi` so that assistants in one workflow can talk to different providers.
+pub trait LlmBackend: Debug + Send + Sync + 'static {
+    fn create_session<'a>(
+        &'a self,
+        name: &'a str,
+        instruction: &'a str,
+        tools: &'a [Tool],
+    ) -> BoxFuture<'a, Result<Session, OpenAIApiError>>;
+    /// Reattaches to a session saved by an earlier launch. Backends which keep
+    /// nothing on the server start a new one.
+    fn resume_session<'a>(
+        &'a self,
+        saved: &'a Session,
+        instruction: &'a str,
+        tools: &'a [Tool],
+    ) -> BoxFuture<'a, Result<Session, OpenAIApiError>> {
+        self.create_session(&saved.name, instruction, tools)
+    }
+    /// Gives back the `(request, answer)` pairs of a saved conversation to a
+    /// backend which keeps the history itself. Others have it on the server.
+    fn restore_history(&self, _session: &Session, _exchanges: &[(String, String)]) {}
+    /// Deletes what `create_session` made on the server.
+    fn delete_session<'a>(
+        &'a self,
+        _session: &'a Session,
+    ) -> BoxFuture<'a, Result<(), OpenAIApiError>> {
+        Box::pin(async { Ok(()) })
+    }
+    fn post_message<'a>(
+        &'a self,
+        session: &'a Session,
+        tag: &'a str,
+        input: &'a str,
+    ) -> BoxFuture<'a, Result<RunId, OpenAIApiError>>;
+    fn await_answer<'a>(
+        &'a self,
+        session: &'a Session,
+        run_id: &'a str,
+    ) -> BoxFuture<'a, Result<Answer, OpenAIApiError>>;
+    /// Stops a run which is not awaited any more.
+    fn cancel<'a>(
+        &'a self,
+        _session: &'a Session,
+        _run_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), OpenAIApiError>> {
+        Box::pin(async { Ok(()) })
+    }
+    /// Time given to each request. `None` waits forever.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+    /// Posts `input` for `tag` and streams the answer. Backends which cannot
+    /// stream answer with a single `Completed`.
+    fn stream_message(
+        self: Arc<Self>,
+        session: Session,
+        tag: String,
+        input: String,
+    ) -> BoxStream<'static, Result<StreamEvent, OpenAIApiError>> {
+        Box::pin(stream::once(async move {
+            let run_id = self.post_message(&session, &tag, &input).await?;
+            self.await_answer(&session, &run_id)
+                .await
+                .map(StreamEvent::Completed)
+        }))
+    }
+}
+
+/// Backend for the Assistants API. OpenAI and Azure only differ in `C`.
+#[derive(Clone, Debug)]
+pub struct AssistantsBackend<C: Config> {
+    client: Client<C>,
+    model: String,
+    polling: Polling,
+}
+
+impl<C: Config> AssistantsBackend<C> {
+    pub fn new(client: Client<C>, model: String, polling: Polling) -> AssistantsBackend<C> {
+        AssistantsBackend {
+            client,
+            model,
+            polling,
+        }
+    }
+}
+
+impl<C: Config + Debug + Send + Sync + 'static> LlmBackend for AssistantsBackend<C> {
+    fn create_session<'a>(
+        &'a self,
+        name: &'a str,
+        instruction: &'a str,
+        tools: &'a [Tool],
+    ) -> BoxFuture<'a, Result<Session, OpenAIApiError>> {
+        Box::pin(async move {
+            let (thread, assistant) =
+                setup_assistant(&self.client, &self.model, name, instruction, tools).await?;
+            Ok(Session {
+                name: name.to_string(),
+                assistant_id: assistant.id,
+                thread_id: thread.id,
+                tools: tools.to_vec(),
+                ..Session::default()
+            })
+        })
+    }
+
+    fn resume_session<'a>(
+        &'a self,
+        saved: &'a Session,
+        instruction: &'a str,
+        tools: &'a [Tool],
+    ) -> BoxFuture<'a, Result<Session, OpenAIApiError>> {
+        Box::pin(async move {
+            // the prompt may have changed since the assistant was created
+            let request = ModifyAssistantRequestArgs::default()
+                .instructions(instruction)
+                .tools(function_tools(tools))
+                .build()?;
+            let assistant = self
+                .client
+                .assistants()
+                .update(&saved.assistant_id, request)
+                .await?;
+            let thread = self.client.threads().retrieve(&saved.thread_id).await?;
+            info!("Resumed {}: {} {}", &saved.name, &assistant.id, &thread.id);
+            Ok(Session {
+                assistant_id: assistant.id,
+                thread_id: thread.id,
+                tools: tools.to_vec(),
+                ..saved.clone()
+            })
+        })
+    }
+
+    fn delete_session<'a>(
+        &'a self,
+        session: &'a Session,
+    ) -> BoxFuture<'a, Result<(), OpenAIApiError>> {
+        Box::pin(async move {
+            info!(
+                "Deleting {}: {} {}",
+                &session.name, &session.assistant_id, &session.thread_id
+            );
+            self.client.threads().delete(&session.thread_id).await?;
+            self.client
+                .assistants()
+                .delete(&session.assistant_id)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn post_message<'a>(
+        &'a self,
+        session: &'a Session,
+        _tag: &'a str,
+        input: &'a str,
+    ) -> BoxFuture<'a, Result<RunId, OpenAIApiError>> {
+        Box::pin(async move {
+            //create a message for the thread
+            let message = CreateMessageRequestArgs::default()
+                //.role("user")
+                .content(input)
+                .build()?;
+            debug!("Create message request args: {:#?}", message);
+            //attach message to the thread
+            let _message_obj = self
+                .client
+                .threads()
+                .messages(&session.thread_id)
+                .create(message)
+                .await?;
+            debug!("messagne created");
+            //create a run for the thread
+            let run_request = CreateRunRequestArgs::default()
+                .assistant_id(&session.assistant_id)
+                .build()?;
+            let run = self
+                .client
+                .threads()
+                .runs(&session.thread_id)
+                .create(run_request)
+                .await?;
+            Ok(run.id)
+        })
+    }
+
+    fn await_answer<'a>(
+        &'a self,
+        session: &'a Session,
+        run_id: &'a str,
+    ) -> BoxFuture<'a, Result<Answer, OpenAIApiError>> {
+        Box::pin(async move {
+            let query = [("limit", "1")]; //limit the list responses to 1 message
+            let thread_id = &session.thread_id;
+            debug!("Start waiting for response");
+            //wait for the run to complete
+            let mut interval = self.polling.interval();
+            let mut tool_calls = vec![];
+            loop {
+                //retrieve the run
+                let run = self
+                    .client
+                    .threads()
+                    .runs(thread_id)
+                    .retrieve(run_id)
+                    .await?;
+                //check the status of the run
+                match run.status {
+                    RunStatus::Completed => {
+                        // once the run is completed we
+                        // get the response from the run
+                        // which will be the first message
+                        // in the thread
+
+                        //retrieve the response from the run
+                        let response = self
+                            .client
+                            .threads()
+                            .messages(thread_id)
+                            .list(&query)
+                            .await?;
+                        //get the message id from the response
+                        let message_id = response
+                            .data
+                            .first()
+                            .ok_or(OpenAIApiError::EmptyResponse)?
+                            .id
+                            .clone();
+                        //get the message from the response
+                        let message = self
+                            .client
+                            .threads()
+                            .messages(thread_id)
+                            .retrieve(&message_id)
+                            .await?;
+                        //get the text and attachments from the content
+                        let mut answer = to_answer(&message.content)?;
+                        answer.tool_calls = tool_calls;
+                        //print the text
+                        info!("--- Response: {}", &answer.text);
+                        return Ok(answer);
+                    }
+
+                    RunStatus::Failed => {
+                        error!("--- Run Failed: {:#?}", run);
+                        return Err(run_failed(&run));
+                    }
+                    RunStatus::Expired => return Err(OpenAIApiError::RunExpired),
+                    RunStatus::Cancelled => return Err(OpenAIApiError::RunCancelled),
+                    RunStatus::Incomplete => return Err(run_incomplete(&run)),
+                    RunStatus::RequiresAction if !session.tools.is_empty() => {
+                        let (outputs, mut calls) = run_tools(&session.tools, &run).await;
+                        tool_calls.append(&mut calls);
+                        let request = SubmitToolOutputsRunRequest {
+                            tool_outputs: outputs,
+                            stream: None,
+                        };
+                        self.client
+                            .threads()
+                            .runs(thread_id)
+                            .submit_tool_outputs(run_id, request)
+                            .await?;
+                        interval = self.polling.interval();
+                        continue;
+                    }
+                    RunStatus::RequiresAction => {
+                        // no tool is available, the run would wait until it expires
+                        self.client.threads().runs(thread_id).cancel(run_id).await?;
+                        return Err(requires_action(&run));
+                    }
+
+                    otherwise => report_status(otherwise),
+                }
+                tokio::time::sleep(interval).await;
+                interval = self.polling.next_interval(interval);
+            }
+        })
+    }
+
+    fn cancel<'a>(
+        &'a self,
+        session: &'a Session,
+        run_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), OpenAIApiError>> {
+        Box::pin(async move {
+            info!("Cancelling run {}", run_id);
+            self.client
+                .threads()
+                .runs(&session.thread_id)
+                .cancel(run_id)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Some(self.polling.timeout())
+    }
+
+    fn stream_message(
+        self: Arc<Self>,
+        session: Session,
+        _tag: String,
+        input: String,
+    ) -> BoxStream<'static, Result<StreamEvent, OpenAIApiError>> {
+        Box::pin(try_stream! {
+            let message = CreateMessageRequestArgs::default().content(input).build()?;
+            self.client
+                .threads()
+                .messages(&session.thread_id)
+                .create(message)
+                .await?;
+            let run_request = CreateRunRequestArgs::default()
+                .assistant_id(&session.assistant_id)
+                .stream(true)
+                .build()?;
+            let mut events = self
+                .client
+                .threads()
+                .runs(&session.thread_id)
+                .create_stream(run_request)
+                .await?;
+            let mut answer = Answer::default();
+            while let Some(event) = events.next().await {
+                match event? {
+                    AssistantStreamEvent::ThreadRunCreated(run) => {
+                        yield StreamEvent::RunCreated(run.id);
+                    }
+                    AssistantStreamEvent::ThreadMessageDelta(delta) => {
+                        for content in delta.delta.content.unwrap_or_default() {
+                            match content {
+                                MessageDeltaContent::Text(MessageDeltaContentTextObject {
+                                    text: Some(MessageDeltaContentText { value: Some(value), .. }),
+                                    ..
+                                }) => {
+                                    answer.text.push_str(&value);
+                                    yield StreamEvent::Delta(value);
+                                }
+                                MessageDeltaContent::ImageFile(MessageDeltaContentImageFileObject {
+                                    image_file: Some(image),
+                                    ..
+                                }) => answer.attachments.push(Attachment::ImageFile {
+                                    file_id: image.file_id,
+                                }),
+                                MessageDeltaContent::ImageUrl(MessageDeltaContentImageUrlObject {
+                                    image_url: Some(image),
+                                    ..
+                                }) => answer.attachments.push(Attachment::ImageUrl { url: image.url }),
+                                MessageDeltaContent::Refusal(MessageDeltaContentRefusalObject {
+                                    refusal: Some(refusal),
+                                    ..
+                                }) => Err(OpenAIApiError::Refused(refusal))?,
+                                _ => (),
+                            }
+                        }
+                    }
+                    AssistantStreamEvent::ThreadRunFailed(run) => {
+                        error!("--- Run Failed: {:#?}", run);
+                        Err(run_failed(&run))?;
+                    }
+                    AssistantStreamEvent::ThreadRunExpired(_) => {
+                        Err(OpenAIApiError::RunExpired)?;
+                    }
+                    AssistantStreamEvent::ThreadRunCancelled(_) => {
+                        Err(OpenAIApiError::RunCancelled)?;
+                    }
+                    AssistantStreamEvent::ThreadRunIncomplete(run) => {
+                        Err(run_incomplete(&run))?;
+                    }
+                    AssistantStreamEvent::ThreadRunRequiresAction(run) if !session.tools.is_empty() => {
+                        let (outputs, mut calls) = run_tools(&session.tools, &run).await;
+                        answer.tool_calls.append(&mut calls);
+                        let request = SubmitToolOutputsRunRequest {
+                            tool_outputs: outputs,
+                            stream: Some(true),
+                        };
+                        events = self
+                            .client
+                            .threads()
+                            .runs(&session.thread_id)
+                            .submit_tool_outputs_stream(&run.id, request)
+                            .await?;
+                    }
+                    AssistantStreamEvent::ThreadRunRequiresAction(run) => {
+                        self.client
+                            .threads()
+                            .runs(&session.thread_id)
+                            .cancel(&run.id)
+                            .await?;
+                        Err(requires_action(&run))?;
+                    }
+                    AssistantStreamEvent::ErrorEvent(e) => {
+                        error!("--- Stream error: {:#?}", e);
+                        Err(OpenAIError::ApiError(e))?;
+                    }
+                    AssistantStreamEvent::Done(_) => break,
+                    _ => (),
+                }
+            }
+            info!("--- Response: {}", &answer.text);
+            yield StreamEvent::Completed(answer);
+        })
+    }
+}
+
+/// Backend for the chat completions API. The history is kept here per session,
+/// with the instruction as its first (system) message, so nothing is left on the server.
+#[derive(Debug)]
+pub struct ChatBackend<C: Config> {
+    client: Client<C>,
+    model: String,
+    histories: Arc<StdMutex<HashMap<String, Vec<ChatCompletionRequestMessage>>>>,
+    answers: StdMutex<HashMap<RunId, Answer>>,
+    runs: AtomicUsize,
+    polling: Polling,
+}
+
+impl<C: Config> ChatBackend<C> {
+    pub fn new(client: Client<C>, model: String, polling: Polling) -> ChatBackend<C> {
+        ChatBackend {
+            client,
+            model,
+            polling,
+            histories: Arc::new(StdMutex::new(HashMap::new())),
+            answers: StdMutex::new(HashMap::new()),
+            runs: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// What a request pushed to a chat history. Unless kept, it is forgotten when
+/// dropped, so that a request which failed or was cancelled or timed out does
+/// not leave a user message without answer, which the next request would
+/// follow with another one.
+#[derive(Debug)]
+struct Pushed {
+    histories: Arc<StdMutex<HashMap<String, Vec<ChatCompletionRequestMessage>>>>,
+    thread_id: String,
+    len: usize,
+    kept: bool,
+}
+
+impl Pushed {
+    fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Pushed {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        if let Some(history) = self.histories.lock().unwrap().get_mut(&self.thread_id) {
+            history.truncate(self.len);
+        }
+    }
+}
+
+impl<C: Config> ChatBackend<C> {
+    // appends a message and returns the whole history to be sent
+    fn push(
+        &self,
+        thread_id: &str,
+        message: ChatCompletionRequestMessage,
+    ) -> Vec<ChatCompletionRequestMessage> {
+        let mut histories = self.histories.lock().unwrap();
+        let history = histories.entry(thread_id.to_string()).or_default();
+        history.push(message);
+        history.clone()
+    }
+
+    fn push_input(&self, thread_id: &str, input: &str) -> Vec<ChatCompletionRequestMessage> {
+        self.push(
+            thread_id,
+            ChatCompletionRequestUserMessage::from(input).into(),
+        )
+    }
+
+    fn push_answer(&self, thread_id: &str, text: &str) {
+        self.push(
+            thread_id,
+            ChatCompletionRequestAssistantMessage::from(text).into(),
+        );
+    }
+
+    // pushes the input of a request, which is taken back unless the request is kept
+    fn push_request(
+        &self,
+        thread_id: &str,
+        input: &str,
+    ) -> (Vec<ChatCompletionRequestMessage>, Pushed) {
+        let messages = self.push_input(thread_id, input);
+        let pushed = Pushed {
+            histories: self.histories.clone(),
+            thread_id: thread_id.to_string(),
+            len: messages.len() - 1,
+            kept: false,
+        };
+        (messages, pushed)
+    }
+
+    // asks without streaming. Tool calls are executed and sent back until a text comes.
+    async fn complete(&self, session: &Session, input: &str) -> Result<Answer, OpenAIApiError> {
+        let (messages, pushed) = self.push_request(&session.thread_id, input);
+        let answer = self.exchange(session, messages).await?;
+        pushed.keep();
+        Ok(answer)
+    }
+
+    async fn exchange(
+        &self,
+        session: &Session,
+        mut messages: Vec<ChatCompletionRequestMessage>,
+    ) -> Result<Answer, OpenAIApiError> {
+        let mut tool_calls = vec![];
+        loop {
+            let mut request = CreateChatCompletionRequestArgs::default();
+            request.model(&self.model).messages(messages);
+            if !session.tools.is_empty() {
+                request.tools(session.tools.iter().map(chat_tool).collect::<Vec<_>>());
+            }
+            let response = self.client.chat().create(request.build()?).await?;
+            let message = response
+                .choices
+                .into_iter()
+                .next()
+                .ok_or(OpenAIApiError::EmptyResponse)?
+                .message;
+            let calls = message.tool_calls.unwrap_or_default();
+            if calls.is_empty() {
+                let text = message.content.unwrap_or_default();
+                info!("--- Response: {}", &text);
+                self.push_answer(&session.thread_id, &text);
+                return Ok(Answer {
+                    text,
+                    tool_calls,
+                    ..Answer::default()
+                });
+            }
+            let request = ChatCompletionRequestAssistantMessage {
+                tool_calls: Some(calls.clone()),
+                ..Default::default()
+            };
+            messages = self.push(&session.thread_id, request.into());
+            for call in calls {
+                let done = call_tool(
+                    &session.tools,
+                    &call.function.name,
+                    &call.function.arguments,
+                )
+                .await;
+                let output = ChatCompletionRequestToolMessage {
+                    content: done.output.clone().into(),
+                    tool_call_id: call.id,
+                };
+                messages = self.push(&session.thread_id, output.into());
+                tool_calls.push(done);
+            }
+        }
+    }
+}
+
+impl<C: Config + Debug + Send + Sync + 'static> LlmBackend for ChatBackend<C> {
+    fn create_session<'a>(
+        &'a self,
+        name: &'a str,
+        instruction: &'a str,
+        tools: &'a [Tool],
+    ) -> BoxFuture<'a, Result<Session, OpenAIApiError>> {
+        Box::pin(async move {
+            let system = ChatCompletionRequestSystemMessage::from(instruction);
+            self.histories
+                .lock()
+                .unwrap()
+                .insert(name.to_string(), vec![system.into()]);
+            Ok(Session {
+                name: name.to_string(),
+                assistant_id: name.to_string(),
+                thread_id: name.to_string(),
+                tools: tools.to_vec(),
+                ..Session::default()
+            })
+        })
+    }
+
+    fn restore_history(&self, session: &Session, exchanges: &[(String, String)]) {
+        for (request, answer) in exchanges {
+            self.push_input(&session.thread_id, request);
+            self.push_answer(&session.thread_id, answer);
+        }
+    }
+
+    fn post_message<'a>(
+        &'a self,
+        session: &'a Session,
+        _tag: &'a str,
+        input: &'a str,
+    ) -> BoxFuture<'a, Result<RunId, OpenAIApiError>> {
+        Box::pin(async move {
+            let answer = self.complete(session, input).await?;
+            let run_id = format!(
+                "{}-{}",
+                &session.thread_id,
+                self.runs.fetch_add(1, Ordering::SeqCst)
+            );
+            self.answers.lock().unwrap().insert(run_id.clone(), answer);
+            Ok(run_id)
+        })
+    }
+
+    fn await_answer<'a>(
+        &'a self,
+        _session: &'a Session,
+        run_id: &'a str,
+    ) -> BoxFuture<'a, Result<Answer, OpenAIApiError>> {
+        Box::pin(async move {
+            self.answers
+                .lock()
+                .unwrap()
+                .remove(run_id)
+                .ok_or_else(|| OpenAIApiError::Api(format!("unknown run {}", run_id)))
+        })
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Some(self.polling.timeout())
+    }
+
+    fn stream_message(
+        self: Arc<Self>,
+        session: Session,
+        _tag: String,
+        input: String,
+    ) -> BoxStream<'static, Result<StreamEvent, OpenAIApiError>> {
+        Box::pin(try_stream! {
+            if session.tools.is_empty() {
+                let (messages, pushed) = self.push_request(&session.thread_id, &input);
+                let chunks = async {
+                    let request = CreateChatCompletionRequestArgs::default()
+                        .model(&self.model)
+                        .messages(messages)
+                        .build()?;
+                    self.client.chat().create_stream(request).await
+                };
+                let mut text = String::new();
+                let mut failed = None;
+                match chunks.await {
+                    Ok(mut chunks) => {
+                        while let Some(chunk) = chunks.next().await {
+                            let chunk = match chunk {
+                                Ok(chunk) => chunk,
+                                Err(e) => {
+                                    failed = Some(e);
+                                    break;
+                                }
+                            };
+                            for choice in chunk.choices {
+                                if let Some(delta) = choice.delta.content {
+                                    text.push_str(&delta);
+                                    yield StreamEvent::Delta(delta);
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => failed = Some(e),
+                }
+                if let Some(e) = failed {
+                    Err(e)?;
+                }
+                info!("--- Response: {}", &text);
+                self.push_answer(&session.thread_id, &text);
+                pushed.keep();
+                yield StreamEvent::Completed(Answer::from(text));
+            } else {
+                // tool calls arrive in pieces when streamed, so an assistant with
+                // tools is asked without streaming
+                let answer = self.complete(&session, &input).await?;
+                yield StreamEvent::Completed(answer);
+            }
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Assistant {
-    thread: ThreadObject,
-    assistant: AssistantObject,
+    backend: Arc<dyn LlmBackend>,
+    session: Session,
 }
 
+/// Backends keyed by the config key they were read from.
+pub type Backends = HashMap<String, Arc<dyn LlmBackend>>;
+
 #[derive(Clone, Debug)]
 pub struct Context {
-    #[cfg(not(feature = "azure_ai"))]
-    client: Client<OpenAIConfig>,
-    #[cfg(feature = "azure_ai")]
-    client: Client<AzureConfig>,
     assistants: HashMap<AssistantName, Assistant>,
 }
 
-#[cfg(not(feature = "azure_ai"))]
-pub type CClient = Client<OpenAIConfig>;
-#[cfg(feature = "azure_ai")]
-pub type CClient = Client<AzureConfig>;
-
 impl Context {
-    pub fn new(client: CClient) -> Context {
+    pub fn new() -> Context {
         Context {
-            client,
             assistants: HashMap::new(),
         }
     }
     pub fn add_assistant(&mut self, name: &String, assistant: Assistant) {
         self.assistants.insert(name.clone(), assistant);
     }
+    pub fn sessions(&self) -> HashMap<AssistantName, Session> {
+        self.assistants
+            .iter()
+            .map(|(name, a)| (name.clone(), a.session.clone()))
+            .collect()
+    }
+    /// Refills the backends which keep the history themselves from a saved
+    /// conversation. Every `FromAi` is paired with the last `ToAi` of the same
+    /// assistant before it, which is the text actually sent.
+    pub fn restore_history(&self, talks: &[Talk]) {
+        let mut requests: HashMap<&AssistantName, String> = HashMap::new();
+        let mut exchanges: HashMap<&AssistantName, Vec<(String, String)>> = HashMap::new();
+        for talk in talks {
+            match talk {
+                Talk::ToAi { name, message, .. } => {
+                    requests.insert(name, message.get_text());
+                }
+                Talk::FromAi { name, message, .. } => {
+                    if let Some(request) = requests.remove(name) {
+                        exchanges
+                            .entry(name)
+                            .or_default()
+                            .push((request, message.get_text()));
+                    }
+                }
+                _ => (),
+            }
+        }
+        for (name, exchanges) in exchanges {
+            if let Some(assistant) = self.assistants.get(name) {
+                assistant
+                    .backend
+                    .restore_history(&assistant.session, &exchanges);
+            }
+        }
+    }
+}
+
+/// Config keys referred by the prompts. Prompts without `service` use `default_key`.
+pub fn service_keys(default_key: &str, prompts: &HashMap<String, Box<Prompt>>) -> Vec<String> {
+    let mut keys: Vec<String> = prompts
+        .values()
+        .filter_map(|p| p.service.clone())
+        .chain(std::iter::once(default_key.to_string()))
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
 }
 
+/// Sets up an assistant for each of `names`. Those in `saved` on the same service
+/// are resumed instead of created, the latest one if several are saved.
 pub async fn connect(
-    config: OpenAi,
-    client: CClient,
+    backends: Backends,
+    default_key: String,
     names: Vec<String>,
     prompts: HashMap<String, Box<Prompt>>,
+    saved: Vec<Session>,
 ) -> Result<Context, OpenAIApiError> {
-    let mut context: Context = Context::new(client);
+    let mut context: Context = Context::new();
     let mut connection_setupped = false;
     for key in names {
         if let Some(prompt) = prompts.get(&key) {
-            info!("Setting up assistant for {}", &key);
-            let (thread, assistant) =
-                setup_assistant(&config, &context.client, &key, &prompt.instruction).await?;
-            context.add_assistant(&key, Assistant { thread, assistant });
+            let service = prompt.service.as_ref().unwrap_or(&default_key);
+            let backend = backends.get(service).ok_or_else(|| {
+                error!("No service {} for {}", service, &key);
+                OpenAIApiError::InvalidConfigKey(service.clone())
+            })?;
+            let mut session = match saved
+                .iter()
+                .rev()
+                .find(|s| s.name == key && &s.service == service)
+            {
+                Some(saved) => {
+                    info!("Resuming assistant for {} on {}", &key, service);
+                    backend
+                        .resume_session(saved, &prompt.instruction, &prompt.tools)
+                        .await?
+                }
+                None => {
+                    info!("Setting up assistant for {} on {}", &key, service);
+                    backend
+                        .create_session(&key, &prompt.instruction, &prompt.tools)
+                        .await?
+                }
+            };
+            session.service = service.clone();
+            context.add_assistant(
+                &key,
+                Assistant {
+                    backend: backend.clone(),
+                    session,
+                },
+            );
             connection_setupped = true;
         }
     }
@@ -128,11 +1052,12 @@ pub async fn connect(
     }
 }
 
-async fn setup_assistant(
-    config: &OpenAi,
-    client: &CClient,
+async fn setup_assistant<C: Config>(
+    client: &Client<C>,
+    model: &str,
     name: &str,
     prompt: &str,
+    tools: &[Tool],
 ) -> Result<(ThreadObject, AssistantObject), OpenAIApiError> {
     //create a thread for the conversation
     let thread_request = CreateThreadRequestArgs::default().build()?;
@@ -140,171 +1065,223 @@ async fn setup_assistant(
 
     let assistant_name = name;
     let instructions = prompt;
-    let model = config.get_model();
 
     //create the assistant
-    let assistant_request = CreateAssistantRequestArgs::default()
+    let mut assistant_request = CreateAssistantRequestArgs::default();
+    assistant_request
         .name(assistant_name)
         .instructions(instructions)
-        .model(&model)
-        .build()?;
+        .model(model);
+    if !tools.is_empty() {
+        assistant_request.tools(function_tools(tools));
+    }
+    let assistant_request = assistant_request.build()?;
     let assistant = client.assistants().create(assistant_request).await?;
     //get the id of the assistant
 
     Ok((thread, assistant))
 }
 
+// resolves when the request is cancelled or its deadline has passed
+async fn interrupted(cancel: &Cancel, deadline: Option<Instant>) -> OpenAIApiError {
+    let timeout = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => future::pending::<()>().await,
+        }
+    };
+    tokio::select! {
+        _ = cancel.cancelled() => OpenAIApiError::RunCancelled,
+        _ = timeout => OpenAIApiError::Timeout,
+    }
+}
+
+async fn stop_run(backend: &Arc<dyn LlmBackend>, session: &Session, run_id: &str) {
+    if let Err(e) = backend.cancel(session, run_id).await {
+        warn!("Failed to cancel run {}: {}", run_id, e);
+    }
+}
+
+/// Asks `name` and waits for the answer until `cancel` or the backend timeout.
+/// The context is only locked to look the assistant up, so other assistants can
+/// be asked meanwhile.
 pub async fn ask(
     context: Arc<Mutex<Context>>,
     name: String,
     tag: String,
     input: String,
-) -> Result<(String, String, String), (String, OpenAIApiError)> {
-    let query = [("limit", "1")]; //limit the list responses to 1 message
-
-    // TODO: handle locked state
-    let ctx = context.lock().await;
-    let client = ctx.client.clone();
-
-    if let Some(interaction) = ctx.assistants.get(&name) {
-        let assistant_id = interaction.assistant.id.clone();
-        let thread_id = interaction.thread.id.clone();
-
-        //create a message for the thread
-        let message = CreateMessageRequestArgs::default()
-            //.role("user")
-            .content(input.clone())
-            .build()
-            .map_err(|e| (name.clone(), e.into()))?;
-        debug!("Create message request args: {:#?}", message);
-        //attach message to the thread
-        let _message_obj = client
-            .threads()
-            .messages(&thread_id)
-            .create(message)
-            .await
-            .map_err(|_| (name.clone(), OpenAIApiError::OpenAIAccessError))?;
-        debug!("messagne created");
-        //create a run for the thread
-        let run_request = CreateRunRequestArgs::default()
-            .assistant_id(assistant_id)
-            .build()
-            .map_err(|e| (name.clone(), e.into()))?;
-        let run = client
-            .threads()
-            .runs(&thread_id)
-            .create(run_request)
-            .await
-            .map_err(|e| (name.clone(), e.into()))?;
-        debug!("Start waiting for response");
-        //wait for the run to complete
-        let mut awaiting_response = true;
-        while awaiting_response {
-            //retrieve the run
-            let run = client
-                .threads()
-                .runs(&thread_id)
-                .retrieve(&run.id)
-                .await
-                .map_err(|e| (name.clone(), e.into()))?;
-            //check the status of the run
-            match run.status {
-                RunStatus::Completed => {
-                    awaiting_response = false;
-                    // once the run is completed we
-                    // get the response from the run
-                    // which will be the first message
-                    // in the thread
-
-                    //retrieve the response from the run
-                    let response = client
-                        .threads()
-                        .messages(&thread_id)
-                        .list(&query)
-                        .await
-                        .map_err(|e| (name.clone(), e.into()))?;
-                    //get the message id from the response
-                    let message_id = response.data.first().unwrap().id.clone();
-                    //get the message from the response
-                    let message = client
-                        .threads()
-                        .messages(&thread_id)
-                        .retrieve(&message_id)
-                        .await
-                        .map_err(|e| (name.clone(), e.into()))?;
-                    //get the content from the message
-                    let content = message.content.first().unwrap();
-
-                    //get the text from the content
-                    let text = match content {
-                        MessageContent::Text(text) => text.text.value.clone(),
-                        _ => {
-                            panic!("non text messages are supported in the terminal")
-                        }
-                    };
-                    //print the text
-                    info!("--- Response: {}", &text);
-                    return Ok((name, tag, text.clone()));
-                }
-
-                RunStatus::Failed => {
-                    awaiting_response = false;
-                    error!("--- Run Failed: {:#?}", run);
-                }
+    cancel: Cancel,
+) -> Result<(String, String, Answer), (String, OpenAIApiError)> {
+    let interaction = context.lock().await.assistants.get(&name).cloned();
+    let Some(interaction) = interaction else {
+        return Err((name.clone(), OpenAIApiError::UnknownAssistant(name)));
+    };
+    let backend = &interaction.backend;
+    let session = &interaction.session;
+    let deadline = backend.timeout().map(|t| Instant::now() + t);
 
-                otherwise => report_status(otherwise),
-            }
+    let run_id = tokio::select! {
+        run_id = backend.post_message(session, &tag, &input) => run_id,
+        reason = interrupted(&cancel, deadline) => Err(reason),
+    }
+    .map_err(|e| (name.clone(), e))?;
+    let answer = tokio::select! {
+        answer = backend.await_answer(session, &run_id) => answer,
+        reason = interrupted(&cancel, deadline) => {
+            stop_run(backend, session, &run_id).await;
+            Err(reason)
         }
-    } else {
-        panic!("No interaction found");
+    };
+    match answer {
+        Ok(answer) => Ok((name, tag, answer)),
+        Err(e) => Err((name, e)),
     }
-
-    Ok((name, tag, String::from("???")))
 }
 
-pub trait AiService<C: Config> {
-    fn create_client(&self) -> Option<Client<C>>;
-}
-
-impl AiService<OpenAIConfig> for OpenAi {
-    fn create_client(&self) -> Option<Client<OpenAIConfig>> {
-        info!("Creating openai client");
-        match self {
-            OpenAi::OpenAiToken { token, .. } => {
-                let token = token.as_str();
-                let oai_config: OpenAIConfig = OpenAIConfig::default().with_api_key(token);
+/// The `(name, tag, answer)` of each query asked by `ask_all`.
+pub type Answered = Vec<(String, String, Answer)>;
 
-                //create a client
+/// What `ask_all` gives: every answer, or the failure with the answers which
+/// came before it.
+pub type Answers = Result<Answered, (String, OpenAIApiError, Answered)>;
 
-                Some(Client::with_config(oai_config))
+/// Asks every `(name, tag, input)` at the same time. The answers are in the
+/// order of `queries`. The first failure cancels the runs still going on.
+pub async fn ask_all(
+    context: Arc<Mutex<Context>>,
+    queries: Vec<(String, String, String)>,
+    cancel: Cancel,
+) -> Answers {
+    let asks = queries.into_iter().map(|(name, tag, input)| {
+        let cancel = cancel.clone();
+        let context = context.clone();
+        async move {
+            let answer = ask(context, name, tag, input, cancel.clone()).await;
+            if answer.is_err() {
+                cancel.cancel();
             }
-            _ => None,
+            answer
         }
+    });
+    let mut answers = future::join_all(asks).await;
+    // the failure which cancelled the others rather than their cancellation
+    let failed = answers
+        .iter()
+        .position(|a| matches!(a, Err((_, e)) if !matches!(e, OpenAIApiError::RunCancelled)))
+        .or_else(|| answers.iter().position(Result::is_err));
+    if let Some(i) = failed {
+        let (name, e) = answers.remove(i).unwrap_err();
+        return Err((name, e, answers.into_iter().flatten().collect()));
     }
+    Ok(answers.into_iter().flatten().collect())
 }
 
-impl AiService<AzureConfig> for OpenAi {
-    fn create_client(&self) -> Option<Client<AzureConfig>> {
-        info!("Creating azure client");
-        match self {
+/// What `ask_stream` yields, tagged with the `(name, tag)` asked.
+pub type StreamItem = Result<(String, String, StreamEvent), (String, OpenAIApiError)>;
+
+/// Same as `ask` but yields the answer piece by piece. The last item is `Completed`.
+pub fn ask_stream(
+    context: Arc<Mutex<Context>>,
+    name: String,
+    tag: String,
+    input: String,
+    cancel: Cancel,
+) -> BoxStream<'static, StreamItem> {
+    Box::pin(stream! {
+        let interaction = context.lock().await.assistants.get(&name).cloned();
+        if let Some(interaction) = interaction {
+            let backend = interaction.backend.clone();
+            let deadline = backend.timeout().map(|t| Instant::now() + t);
+            let mut events =
+                backend
+                    .clone()
+                    .stream_message(interaction.session.clone(), tag.clone(), input);
+            let mut run_id: Option<RunId> = None;
+            loop {
+                let event = tokio::select! {
+                    event = events.next() => event,
+                    reason = interrupted(&cancel, deadline) => {
+                        if let Some(run_id) = &run_id {
+                            stop_run(&backend, &interaction.session, run_id).await;
+                        }
+                        Some(Err(reason))
+                    }
+                };
+                match event {
+                    None => break,
+                    Some(Ok(StreamEvent::RunCreated(id))) => run_id = Some(id),
+                    Some(Ok(event)) => yield Ok((name.clone(), tag.clone(), event)),
+                    Some(Err(e)) => {
+                        yield Err((name.clone(), e));
+                        break;
+                    }
+                }
+            }
+        } else {
+            yield Err((name.clone(), OpenAIApiError::UnknownAssistant(name.clone())));
+        }
+    })
+}
+
+impl OpenAi {
+    pub fn create_backend(&self) -> Result<Arc<dyn LlmBackend>, OpenAIApiError> {
+        let backend = match self {
+            OpenAi::OpenAiToken {
+                token,
+                model,
+                api_base,
+                api,
+                polling,
+            } => {
+                info!("Creating openai client");
+                let mut oai_config: OpenAIConfig = OpenAIConfig::default().with_api_key(token);
+                if let Some(api_base) = api_base {
+                    oai_config = oai_config.with_api_base(api_base);
+                }
+                create_backend(Client::with_config(oai_config), model.clone(), api, polling)
+            }
             OpenAi::AzureAiToken {
                 key,
                 endpoint,
                 deployment_id,
                 api_version,
+                api,
+                polling,
             } => {
+                info!("Creating azure client");
                 let azure_config: AzureConfig = AzureConfig::default()
                     .with_api_key(key)
                     //with_endpoint(endpoint)
                     .with_api_base(endpoint)
                     .with_deployment_id(deployment_id)
                     .with_api_version(api_version);
-
-                //create a client
-                Some(Client::with_config(azure_config))
+                // Azure takes the deployment name as model.
+                create_backend(
+                    Client::with_config(azure_config),
+                    deployment_id.clone(),
+                    api,
+                    polling,
+                )
             }
-            _ => None,
-        }
+            OpenAi::Replay { fixture } => {
+                info!("Replaying {}", fixture);
+                Arc::new(ReplayBackend::from_file(Path::new(fixture))?)
+            }
+        };
+        Ok(backend)
+    }
+}
+
+fn create_backend<C: Config + Debug + Send + Sync + 'static>(
+    client: Client<C>,
+    model: String,
+    api: &Api,
+    polling: &Polling,
+) -> Arc<dyn LlmBackend> {
+    let polling = polling.clone();
+    match api {
+        Api::Assistants => Arc::new(AssistantsBackend::new(client, model, polling)),
+        Api::ChatCompletions => Arc::new(ChatBackend::new(client, model, polling)),
     }
 }
 
@@ -328,25 +1305,485 @@ pub fn report_status(status: RunStatus) {
         RunStatus::InProgress => {
             info!("--- Waiting for response...");
         }
-        _ => panic!("should not reach here"),
+        RunStatus::Completed => {
+            info!("--- Run Completed");
+        }
+        RunStatus::Failed => {
+            info!("--- Run Failed");
+        }
+        RunStatus::Incomplete => {
+            info!("--- Run Incomplete");
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Error)]
 pub enum OpenAIApiError {
+    #[error("no assistant could be set up")]
     OpenAIAccessError,
+    #[error("http error (status: {status:?}): {message}")]
+    Http {
+        status: Option<u16>,
+        message: String,
+    },
+    #[error("rate limited: {0}")]
+    RateLimited(String),
+    #[error("authentication failed: {0}")]
+    AuthFailed(String),
+    #[error("api error: {0}")]
+    Api(String),
+    #[error("run failed ({code}): {message}")]
+    RunFailed { code: String, message: String },
+    #[error("run expired")]
+    RunExpired,
+    #[error("run cancelled")]
+    RunCancelled,
+    #[error("no answer before the deadline")]
+    Timeout,
+    #[error("run incomplete: {0}")]
+    RunIncomplete(String),
+    #[error("run requires unsupported action: {0}")]
+    RequiresAction(String),
+    #[error("refused: {0}")]
+    Refused(String),
+    #[error("no message in the response")]
+    EmptyResponse,
+    #[error("unknown assistant: {0}")]
+    UnknownAssistant(String),
+    #[error("invalid config key: {0}")]
+    InvalidConfigKey(String),
+    #[error("io error: {0}")]
+    Io(String),
 }
 
 impl From<OpenAIError> for OpenAIApiError {
     fn from(error: OpenAIError) -> OpenAIApiError {
-        dbg!(error);
-        OpenAIAccessError
+        debug!("{:?}", &error);
+        match error {
+            OpenAIError::Reqwest(e) => match e.status().map(|s| s.as_u16()) {
+                Some(401) | Some(403) => OpenAIApiError::AuthFailed(e.to_string()),
+                Some(429) => OpenAIApiError::RateLimited(e.to_string()),
+                status => OpenAIApiError::Http {
+                    status,
+                    message: e.to_string(),
+                },
+            },
+            OpenAIError::ApiError(e) => {
+                let code = e.code.clone().unwrap_or_default();
+                let kind = e.r#type.clone().unwrap_or_default();
+                if code == "invalid_api_key" || kind == "authentication_error" {
+                    OpenAIApiError::AuthFailed(e.message)
+                } else if code == "rate_limit_exceeded" || kind == "rate_limit_error" {
+                    OpenAIApiError::RateLimited(e.message)
+                } else {
+                    OpenAIApiError::Api(e.to_string())
+                }
+            }
+            otherwise => OpenAIApiError::Api(otherwise.to_string()),
+        }
     }
 }
 
 impl From<std::io::Error> for OpenAIApiError {
     fn from(error: std::io::Error) -> OpenAIApiError {
-        dbg!(error);
-        OpenAIApiError::OpenAIAccessError
+        OpenAIApiError::Io(error.to_string())
+    }
+}
+
+fn run_incomplete(run: &RunObject) -> OpenAIApiError {
+    OpenAIApiError::RunIncomplete(
+        run.incomplete_details
+            .as_ref()
+            .map(|d| format!("{:?}", d.reason))
+            .unwrap_or_default(),
+    )
+}
+
+// names of the functions a run asked to call
+fn requires_action(run: &RunObject) -> OpenAIApiError {
+    let names = run
+        .required_action
+        .as_ref()
+        .map(|a| {
+            a.submit_tool_outputs
+                .tool_calls
+                .iter()
+                .map(|c| c.function.name.clone())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+    OpenAIApiError::RequiresAction(names)
+}
+
+fn chat_tool(tool: &Tool) -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: tool.name.clone(),
+            description: tool.description.clone(),
+            parameters: schema(tool),
+            strict: None,
+        },
+    }
+}
+
+fn function_tools(tools: &[Tool]) -> Vec<AssistantTools> {
+    tools.iter().map(|t| chat_tool(t).function.into()).collect()
+}
+
+// executes the tool calls a run is waiting for
+async fn run_tools(tools: &[Tool], run: &RunObject) -> (Vec<ToolsOutputs>, Vec<ToolCall>) {
+    let mut outputs = vec![];
+    let mut calls = vec![];
+    let requested = run
+        .required_action
+        .iter()
+        .flat_map(|a| a.submit_tool_outputs.tool_calls.iter());
+    for call in requested {
+        let done = call_tool(tools, &call.function.name, &call.function.arguments).await;
+        outputs.push(ToolsOutputs {
+            tool_call_id: Some(call.id.clone()),
+            output: Some(done.output.clone()),
+        });
+        calls.push(done);
+    }
+    (outputs, calls)
+}
+
+fn to_answer(contents: &Vec<MessageContent>) -> Result<Answer, OpenAIApiError> {
+    if contents.is_empty() {
+        return Err(OpenAIApiError::EmptyResponse);
+    }
+    let mut answer = Answer::default();
+    for content in contents {
+        match content {
+            MessageContent::Text(text) => answer.text.push_str(&text.text.value),
+            MessageContent::ImageFile(image) => answer.attachments.push(Attachment::ImageFile {
+                file_id: image.image_file.file_id.clone(),
+            }),
+            MessageContent::ImageUrl(image) => answer.attachments.push(Attachment::ImageUrl {
+                url: image.image_url.url.clone(),
+            }),
+            MessageContent::Refusal(refusal) => {
+                return Err(OpenAIApiError::Refused(refusal.refusal.clone()))
+            }
+        }
+    }
+    Ok(answer)
+}
+
+// RunFailed from the last_error of a failed run
+fn run_failed(run: &RunObject) -> OpenAIApiError {
+    match &run.last_error {
+        Some(e) => OpenAIApiError::RunFailed {
+            code: format!("{:?}", e.code),
+            message: e.message.clone(),
+        },
+        None => OpenAIApiError::RunFailed {
+            code: "unknown".to_string(),
+            message: "".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::read_config;
+    use async_openai::error::ApiError;
+
+    #[test]
+    fn test_api_error() {
+        let api_error = |code: &str, kind: &str| {
+            OpenAIError::ApiError(ApiError {
+                message: "message".to_string(),
+                r#type: Some(kind.to_string()),
+                param: None,
+                code: Some(code.to_string()),
+            })
+        };
+        assert!(matches!(
+            api_error("invalid_api_key", "invalid_request_error").into(),
+            OpenAIApiError::AuthFailed(_)
+        ));
+        assert!(matches!(
+            api_error("rate_limit_exceeded", "requests").into(),
+            OpenAIApiError::RateLimited(_)
+        ));
+        assert!(matches!(
+            api_error("model_not_found", "invalid_request_error").into(),
+            OpenAIApiError::Api(_)
+        ));
+    }
+
+    // never answers, remembers the cancelled run
+    #[derive(Debug, Default)]
+    struct Silent {
+        cancelled: StdMutex<Option<RunId>>,
+    }
+
+    impl LlmBackend for Silent {
+        fn create_session<'a>(
+            &'a self,
+            name: &'a str,
+            _instruction: &'a str,
+            _tools: &'a [Tool],
+        ) -> BoxFuture<'a, Result<Session, OpenAIApiError>> {
+            Box::pin(async move {
+                Ok(Session {
+                    name: name.to_string(),
+                    ..Session::default()
+                })
+            })
+        }
+
+        fn post_message<'a>(
+            &'a self,
+            _session: &'a Session,
+            _tag: &'a str,
+            _input: &'a str,
+        ) -> BoxFuture<'a, Result<RunId, OpenAIApiError>> {
+            Box::pin(async { Ok("run-1".to_string()) })
+        }
+
+        fn await_answer<'a>(
+            &'a self,
+            _session: &'a Session,
+            _run_id: &'a str,
+        ) -> BoxFuture<'a, Result<Answer, OpenAIApiError>> {
+            Box::pin(future::pending())
+        }
+
+        fn cancel<'a>(
+            &'a self,
+            _session: &'a Session,
+            run_id: &'a str,
+        ) -> BoxFuture<'a, Result<(), OpenAIApiError>> {
+            *self.cancelled.lock().unwrap() = Some(run_id.to_string());
+            Box::pin(async { Ok(()) })
+        }
+
+        fn timeout(&self) -> Option<Duration> {
+            Some(Duration::from_millis(50))
+        }
+    }
+
+    #[test]
+    fn test_ask_interrupted() {
+        let backend = Arc::new(Silent::default());
+        let mut context = Context::new();
+        context.add_assistant(
+            &"king".to_string(),
+            Assistant {
+                backend: backend.clone(),
+                session: Session::default(),
+            },
+        );
+        let context = Arc::new(Mutex::new(context));
+        let ask_king = |cancel: Cancel| {
+            let context = context.clone();
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async move {
+                ask(context, "king".into(), "k1".into(), "hi".into(), cancel).await
+            })
+        };
+
+        assert!(matches!(
+            ask_king(Cancel::default()),
+            Err((_, OpenAIApiError::Timeout))
+        ));
+        assert_eq!(
+            *backend.cancelled.lock().unwrap(),
+            Some("run-1".to_string())
+        );
+
+        let cancel = Cancel::default();
+        cancel.cancel();
+        assert!(matches!(
+            ask_king(cancel),
+            Err((_, OpenAIApiError::RunCancelled))
+        ));
+
+        // accepts the request but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config =
+            OpenAIConfig::new().with_api_base(format!("http://{}", listener.local_addr().unwrap()));
+        let chat = Arc::new(ChatBackend::new(
+            Client::with_config(config),
+            "model".to_string(),
+            Polling::default(),
+        ));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let session = chat.create_session("king", "be a king", &[]).await.unwrap();
+            let mut context = Context::new();
+            context.add_assistant(
+                &"king".to_string(),
+                Assistant {
+                    backend: chat.clone(),
+                    session,
+                },
+            );
+            let context = Arc::new(Mutex::new(context));
+            let cancel = Cancel::default();
+            let later = cancel.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                later.cancel();
+            });
+            let asked = ask(context, "king".into(), "k1".into(), "hi".into(), cancel).await;
+            assert!(matches!(asked, Err((_, OpenAIApiError::RunCancelled))));
+        });
+        // the question without answer is taken back
+        assert_eq!(chat.histories.lock().unwrap()["king"].len(), 1);
+    }
+
+    #[test]
+    fn test_chat_rollback() {
+        // nothing listens there
+        let config = OpenAIConfig::new().with_api_base("http://127.0.0.1:9");
+        let backend = ChatBackend::new(
+            Client::with_config(config),
+            "model".to_string(),
+            Polling::default(),
+        );
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let session = backend
+                .create_session("king", "be a king", &[])
+                .await
+                .unwrap();
+            assert!(backend.complete(&session, "hi").await.is_err());
+            assert!(backend.complete(&session, "hi again").await.is_err());
+        });
+        // only the instruction is left
+        assert_eq!(backend.histories.lock().unwrap()["king"].len(), 1);
+    }
+
+    #[test]
+    fn test_restore_history() {
+        let config = OpenAIConfig::new().with_api_base("http://127.0.0.1:9");
+        let backend = Arc::new(ChatBackend::new(
+            Client::with_config(config),
+            "model".to_string(),
+            Polling::default(),
+        ));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let session = rt
+            .block_on(backend.create_session("king", "be a king", &[]))
+            .unwrap();
+        let mut context = Context::new();
+        context.add_assistant(
+            &"king".to_string(),
+            Assistant {
+                backend: backend.clone(),
+                session,
+            },
+        );
+        let talk = |to_ai: bool, name: &str, text: &str| {
+            let (name, tag, message) = (
+                name.to_string(),
+                "k1".to_string(),
+                crate::Content::Text(text.to_string()),
+            );
+            if to_ai {
+                Talk::ToAi { name, tag, message }
+            } else {
+                Talk::FromAi { name, tag, message }
+            }
+        };
+        context.restore_history(&[
+            talk(true, "king", "rendered"),
+            talk(true, "king", "sent"),
+            talk(true, "queen", "to the queen"),
+            talk(false, "king", "answer"),
+            talk(true, "king", "not answered"),
+        ]);
+        let expected: Vec<ChatCompletionRequestMessage> = vec![
+            ChatCompletionRequestSystemMessage::from("be a king").into(),
+            ChatCompletionRequestUserMessage::from("sent").into(),
+            ChatCompletionRequestAssistantMessage::from("answer").into(),
+        ];
+        assert_eq!(backend.histories.lock().unwrap()["king"], expected);
+    }
+
+    #[test]
+    fn test_polling() {
+        let polling: Polling =
+            serde_yaml::from_str("interval_ms: 100\nmax_interval_ms: 300").unwrap();
+        assert_eq!(polling.timeout_secs, Polling::default().timeout_secs);
+        let mut interval = polling.interval();
+        let mut intervals = vec![];
+        for _ in 0..4 {
+            intervals.push(interval.as_millis());
+            interval = polling.next_interval(interval);
+        }
+        assert_eq!(intervals, vec![100, 200, 300, 300]);
+    }
+
+    #[test]
+    fn test_to_answer() {
+        let contents: Vec<MessageContent> = serde_json::from_str(
+            r#"[
+                {"type": "text", "text": {"value": "see the chart", "annotations": []}},
+                {"type": "image_file", "image_file": {"file_id": "file-1"}}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            to_answer(&contents).unwrap(),
+            Answer {
+                text: "see the chart".to_string(),
+                attachments: vec![Attachment::ImageFile {
+                    file_id: "file-1".to_string()
+                }],
+                tool_calls: vec![],
+            }
+        );
+        assert!(matches!(
+            to_answer(&vec![]),
+            Err(OpenAIApiError::EmptyResponse)
+        ));
+        let refused: Vec<MessageContent> =
+            serde_json::from_str(r#"[{"type": "refusal", "refusal": "no"}]"#).unwrap();
+        assert!(matches!(
+            to_answer(&refused),
+            Err(OpenAIApiError::Refused(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_api() {
+        let input = r#"
+openai:
+  !OpenAiToken
+  token: xxx
+  model: gpt-4o
+local:
+  !OpenAiToken
+  token: xxx
+  model: llama3
+  api_base: http://localhost:8080/v1
+  api: ChatCompletions
+        "#;
+        let openai: OpenAi = read_config(Some(&"openai".to_string()), input).unwrap();
+        assert!(matches!(
+            openai,
+            OpenAi::OpenAiToken {
+                api: Api::Assistants,
+                api_base: None,
+                ..
+            }
+        ));
+        let local: OpenAi = read_config(Some(&"local".to_string()), input).unwrap();
+        assert!(matches!(
+            local,
+            OpenAi::OpenAiToken {
+                api: Api::ChatCompletions,
+                api_base: Some(_),
+                ..
+            }
+        ));
     }
 }
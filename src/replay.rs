@@ -0,0 +1,320 @@
+use crate::openai_api::StreamEvent;
+use crate::openai_api::{Answer, BoxFuture, LlmBackend, OpenAIApiError, RunId, Session};
+use crate::scenario::Tool;
+use crate::{AssistantName, Content, Tag, Talk};
+use futures::stream::{BoxStream, StreamExt};
+use log::{error, info};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type Key = (AssistantName, Tag, String);
+
+fn is_jsonl(path: &Path) -> bool {
+    path.extension().map(|e| e == "jsonl").unwrap_or(false)
+}
+
+/// Reads talks saved as conversation.yaml, or one json `Talk` per line for `.jsonl`.
+pub fn read_talks(path: &Path) -> Result<Vec<Talk>, io::Error> {
+    let content = fs::read_to_string(path)?;
+    let to_io = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    if is_jsonl(path) {
+        content
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| serde_json::from_str(l).map_err(|e| to_io(e.to_string())))
+            .collect()
+    } else {
+        serde_yaml::from_str(&content).map_err(|e| to_io(e.to_string()))
+    }
+}
+
+pub fn write_talks(path: &Path, talks: &Vec<Talk>) -> Result<(), io::Error> {
+    let to_io = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let content = if is_jsonl(path) {
+        let lines: Result<Vec<String>, _> = talks.iter().map(serde_json::to_string).collect();
+        lines.map_err(|e| to_io(e.to_string()))?.join("\n") + "\n"
+    } else {
+        serde_yaml::to_string(talks).map_err(|e| to_io(e.to_string()))?
+    };
+    fs::write(path, content)
+}
+
+/// Pairs every `FromAi` with the last `ToAi` of the same assistant before it.
+/// The last `ToAi` is the text actually sent, as `LoadInput` and `QueryAi` both push one.
+fn exchanges(talks: &Vec<Talk>) -> HashMap<Key, Vec<String>> {
+    let mut requests: HashMap<AssistantName, String> = HashMap::new();
+    let mut res: HashMap<Key, Vec<String>> = HashMap::new();
+    for talk in talks {
+        match talk {
+            Talk::ToAi { name, message, .. } => {
+                requests.insert(name.clone(), message.get_text());
+            }
+            Talk::FromAi { name, tag, message } => {
+                if let Some(request) = requests.remove(name) {
+                    res.entry((name.clone(), tag.clone(), request))
+                        .or_default()
+                        .push(message.get_text());
+                }
+            }
+            _ => (),
+        }
+    }
+    res
+}
+
+/// Answers from a recorded conversation keyed by `(name, tag, request text)`.
+/// The same request asked several times gets the recorded answers in order,
+/// and the last one once they are used up.
+#[derive(Debug)]
+pub struct ReplayBackend {
+    answers: Mutex<HashMap<Key, Vec<String>>>,
+    pending: Mutex<HashMap<RunId, String>>,
+    runs: AtomicUsize,
+}
+
+impl ReplayBackend {
+    pub fn new(talks: &Vec<Talk>) -> ReplayBackend {
+        ReplayBackend {
+            answers: Mutex::new(exchanges(talks)),
+            pending: Mutex::new(HashMap::new()),
+            runs: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<ReplayBackend, io::Error> {
+        Ok(ReplayBackend::new(&read_talks(path)?))
+    }
+}
+
+impl LlmBackend for ReplayBackend {
+    fn create_session<'a>(
+        &'a self,
+        name: &'a str,
+        _instruction: &'a str,
+        tools: &'a [Tool],
+    ) -> BoxFuture<'a, Result<Session, OpenAIApiError>> {
+        Box::pin(async move {
+            Ok(Session {
+                name: name.to_string(),
+                assistant_id: name.to_string(),
+                thread_id: name.to_string(),
+                tools: tools.to_vec(),
+                ..Session::default()
+            })
+        })
+    }
+
+    fn post_message<'a>(
+        &'a self,
+        session: &'a Session,
+        tag: &'a str,
+        input: &'a str,
+    ) -> BoxFuture<'a, Result<RunId, OpenAIApiError>> {
+        Box::pin(async move {
+            let key = (session.name.clone(), tag.to_string(), input.to_string());
+            let mut answers = self.answers.lock().unwrap();
+            let recorded = answers.get_mut(&key).ok_or_else(|| {
+                error!("No recorded answer for {:?}", &key);
+                OpenAIApiError::Api(format!("no recorded answer for ({}, {})", &key.0, &key.1))
+            })?;
+            let answer = if recorded.len() > 1 {
+                recorded.remove(0)
+            } else {
+                recorded[0].clone()
+            };
+            let run_id = format!("replay-{}", self.runs.fetch_add(1, Ordering::SeqCst));
+            self.pending.lock().unwrap().insert(run_id.clone(), answer);
+            Ok(run_id)
+        })
+    }
+
+    fn await_answer<'a>(
+        &'a self,
+        _session: &'a Session,
+        run_id: &'a str,
+    ) -> BoxFuture<'a, Result<Answer, OpenAIApiError>> {
+        Box::pin(async move {
+            self.pending
+                .lock()
+                .unwrap()
+                .remove(run_id)
+                .map(Answer::from)
+                .ok_or_else(|| OpenAIApiError::Api(format!("unknown run {}", run_id)))
+        })
+    }
+}
+
+/// Passes everything to `inner` and appends each exchange to `path`
+/// in the format `ReplayBackend` reads.
+#[derive(Debug)]
+pub struct RecordBackend {
+    inner: Arc<dyn LlmBackend>,
+    path: PathBuf,
+    talks: Arc<Mutex<Vec<Talk>>>,
+    pending: Mutex<HashMap<RunId, (Tag, String)>>,
+}
+
+impl RecordBackend {
+    /// Backends sharing `talks` record into the same file.
+    pub fn new(
+        inner: Arc<dyn LlmBackend>,
+        path: PathBuf,
+        talks: Arc<Mutex<Vec<Talk>>>,
+    ) -> RecordBackend {
+        RecordBackend {
+            inner,
+            path,
+            talks,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record(&self, name: &str, tag: &str, input: &str, answer: &str) {
+        let mut talks = self.talks.lock().unwrap();
+        talks.push(Talk::ToAi {
+            name: name.to_string(),
+            tag: tag.to_string(),
+            message: Content::Text(input.to_string()),
+        });
+        talks.push(Talk::FromAi {
+            name: name.to_string(),
+            tag: tag.to_string(),
+            message: Content::Text(answer.to_string()),
+        });
+        match write_talks(&self.path, &talks) {
+            Ok(()) => info!("recorded ({}, {}) to {:?}", name, tag, &self.path),
+            Err(e) => error!("failed to record to {:?}: {}", &self.path, e),
+        }
+    }
+}
+
+impl LlmBackend for RecordBackend {
+    fn create_session<'a>(
+        &'a self,
+        name: &'a str,
+        instruction: &'a str,
+        tools: &'a [Tool],
+    ) -> BoxFuture<'a, Result<Session, OpenAIApiError>> {
+        self.inner.create_session(name, instruction, tools)
+    }
+
+    fn resume_session<'a>(
+        &'a self,
+        saved: &'a Session,
+        instruction: &'a str,
+        tools: &'a [Tool],
+    ) -> BoxFuture<'a, Result<Session, OpenAIApiError>> {
+        self.inner.resume_session(saved, instruction, tools)
+    }
+
+    fn restore_history(&self, session: &Session, exchanges: &[(String, String)]) {
+        self.inner.restore_history(session, exchanges)
+    }
+
+    fn delete_session<'a>(
+        &'a self,
+        session: &'a Session,
+    ) -> BoxFuture<'a, Result<(), OpenAIApiError>> {
+        self.inner.delete_session(session)
+    }
+
+    fn post_message<'a>(
+        &'a self,
+        session: &'a Session,
+        tag: &'a str,
+        input: &'a str,
+    ) -> BoxFuture<'a, Result<RunId, OpenAIApiError>> {
+        Box::pin(async move {
+            let run_id = self.inner.post_message(session, tag, input).await?;
+            self.pending
+                .lock()
+                .unwrap()
+                .insert(run_id.clone(), (tag.to_string(), input.to_string()));
+            Ok(run_id)
+        })
+    }
+
+    fn await_answer<'a>(
+        &'a self,
+        session: &'a Session,
+        run_id: &'a str,
+    ) -> BoxFuture<'a, Result<Answer, OpenAIApiError>> {
+        Box::pin(async move {
+            let answer = self.inner.await_answer(session, run_id).await?;
+            if let Some((tag, input)) = self.pending.lock().unwrap().remove(run_id) {
+                self.record(&session.name, &tag, &input, &answer.text);
+            }
+            Ok(answer)
+        })
+    }
+
+    fn cancel<'a>(
+        &'a self,
+        session: &'a Session,
+        run_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), OpenAIApiError>> {
+        self.inner.cancel(session, run_id)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.inner.timeout()
+    }
+
+    fn stream_message(
+        self: Arc<Self>,
+        session: Session,
+        tag: String,
+        input: String,
+    ) -> BoxStream<'static, Result<StreamEvent, OpenAIApiError>> {
+        let events = self
+            .inner
+            .clone()
+            .stream_message(session.clone(), tag.clone(), input.clone());
+        Box::pin(events.inspect(move |event| {
+            if let Ok(StreamEvent::Completed(answer)) = event {
+                self.record(&session.name, &tag, &input, &answer.text);
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn talk(to_ai: bool, name: &str, tag: &str, text: &str) -> Talk {
+        let (name, tag, message) = (
+            name.to_string(),
+            tag.to_string(),
+            Content::Text(text.to_string()),
+        );
+        if to_ai {
+            Talk::ToAi { name, tag, message }
+        } else {
+            Talk::FromAi { name, tag, message }
+        }
+    }
+
+    #[test]
+    fn test_exchanges() {
+        let talks = vec![
+            talk(true, "king", "k1", "rendered"),
+            talk(true, "king", "k1", "edited"),
+            talk(false, "king", "k1", "answer1"),
+            talk(true, "king", "k1", "edited"),
+            talk(false, "king", "k1", "answer2"),
+            talk(false, "queen", "q1", "no request"),
+        ];
+        let ex = exchanges(&talks);
+        assert_eq!(ex.len(), 1);
+        assert_eq!(
+            ex.get(&("king".to_string(), "k1".to_string(), "edited".to_string())),
+            Some(&vec!["answer1".to_string(), "answer2".to_string()])
+        );
+    }
+}
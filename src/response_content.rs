@@ -1,10 +1,12 @@
+use std::ops::Range;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Mark {
     Marker { text: String, lang: Option<String> },
     Content { text: String, lang: Option<String> },
 }
 
-pub fn split_code(source: &str, markers: &Vec<regex::Regex>) -> Vec<Mark> {
+pub fn split_code(source: &str, markers: &[regex::Regex]) -> Vec<Mark> {
     let mut curr_pos: usize = 0; // index to source
     let max = source.len();
     let mut result = Vec::new();
@@ -50,6 +52,161 @@ pub fn split_code(source: &str, markers: &Vec<regex::Regex>) -> Vec<Mark> {
     result
 }
 
+/// A fenced code block of a Markdown document.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeBlock {
+    /// The first word of the info string, e.g. `rust` for ```` ```rust title="a" ````.
+    pub lang: Option<String>,
+    /// Everything after the opening fence, trimmed.
+    pub info: String,
+    /// The lines between the fences, without the indentation of the opening fence.
+    pub code: String,
+    /// Bytes of the source from the opening fence to the end of the closing one.
+    pub range: Range<usize>,
+    /// Bytes of the source between the fences.
+    pub code_range: Range<usize>,
+    /// 1-based lines of the opening fence and of the closing fence, or of the
+    /// last line if the block is not closed.
+    pub lines: (usize, usize),
+}
+
+/// Character, length and indentation of a code fence line, and what follows it.
+fn fence(line: &str) -> Option<(char, usize, usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let rest = &line[indent..];
+    let c = rest.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = rest.len() - rest.trim_start_matches(c).len();
+    if len < 3 {
+        return None;
+    }
+    Some((c, len, indent, &rest[len..]))
+}
+
+/// Every fenced code block of `source`, as CommonMark finds them: a fence is
+/// at least three backticks or tildes indented by less than four spaces, and
+/// the block ends at a fence of the same character at least as long with
+/// nothing after it, or at the end of the document. Backticks cannot be in
+/// the info string of a backtick fence, so ```` ```a`b```` is not a fence.
+/// Block quotes and list items are not looked into.
+pub fn code_blocks(source: &str) -> Vec<CodeBlock> {
+    struct Open {
+        c: char,
+        len: usize,
+        indent: usize,
+        info: String,
+        start: usize,
+        code_start: usize,
+        line: usize,
+        code: String,
+    }
+    let mut blocks = vec![];
+    let mut open: Option<Open> = None;
+    let mut pos = 0;
+    let mut line_no = 0;
+    for raw in source.split_inclusive('\n') {
+        line_no += 1;
+        let line = raw.trim_end_matches('\n').trim_end_matches('\r');
+        let line_start = pos;
+        pos += raw.len();
+        match open.take() {
+            None => {
+                open = fence(line).and_then(|(c, len, indent, info)| {
+                    (c == '~' || !info.contains('`')).then(|| Open {
+                        c,
+                        len,
+                        indent,
+                        info: info.trim().to_string(),
+                        start: line_start,
+                        code_start: pos,
+                        line: line_no,
+                        code: String::new(),
+                    })
+                });
+            }
+            Some(mut block) => match fence(line) {
+                Some((c, len, _, rest))
+                    if c == block.c && len >= block.len && rest.trim().is_empty() =>
+                {
+                    blocks.push(CodeBlock {
+                        lang: block.info.split_whitespace().next().map(str::to_string),
+                        info: block.info,
+                        code: block.code,
+                        range: block.start..line_start + line.len(),
+                        code_range: block.code_start..line_start,
+                        lines: (block.line, line_no),
+                    });
+                }
+                _ => {
+                    let indent = raw.len() - raw.trim_start_matches(' ').len();
+                    block.code.push_str(&raw[indent.min(block.indent)..]);
+                    open = Some(block);
+                }
+            },
+        }
+    }
+    if let Some(block) = open {
+        blocks.push(CodeBlock {
+            lang: block.info.split_whitespace().next().map(str::to_string),
+            info: block.info,
+            code: block.code,
+            range: block.start..source.len(),
+            code_range: block.code_start.min(source.len())..source.len(),
+            lines: (block.line, line_no),
+        });
+    }
+    blocks
+}
+
+/// `code_blocks` in the form of `split_code`: the fences are markers, the
+/// code is content in the language of its block and the rest is content
+/// without one. The texts put together are the source.
+pub fn marks(source: &str) -> Vec<Mark> {
+    let mut result = vec![];
+    let mut pos = 0;
+    let mut text = |range: Range<usize>, lang: Option<String>, marker: bool| {
+        if range.is_empty() {
+            return;
+        }
+        let text = source[range].to_string();
+        result.push(if marker {
+            Mark::Marker { text, lang }
+        } else {
+            Mark::Content { text, lang }
+        });
+    };
+    for block in code_blocks(source) {
+        text(pos..block.range.start, None, false);
+        text(
+            block.range.start..block.code_range.start,
+            block.lang.clone(),
+            true,
+        );
+        text(block.code_range.clone(), block.lang, false);
+        text(block.code_range.end..block.range.end, None, true);
+        pos = block.range.end;
+    }
+    text(pos..source.len(), None, false);
+    result
+}
+
+/// Language and text of the first fenced code block with a language, e.g. ```rust.
+pub fn extract_code(source: &str) -> Option<(String, String)> {
+    code_blocks(source)
+        .into_iter()
+        .find_map(|block| Some((block.lang?, block.code)))
+}
+
+/// Language and text of every fenced code block with a language, in order.
+pub fn extract_code_blocks(source: &str) -> Vec<(String, String)> {
+    code_blocks(source)
+        .into_iter()
+        .filter_map(|block| Some((block.lang?, block.code)))
+        .collect()
+}
+
 pub fn get_content(contents: Vec<Mark>) -> Option<Mark> {
     let mut res = None;
     for c in contents {
@@ -61,8 +218,11 @@ pub fn get_content(contents: Vec<Mark>) -> Option<Mark> {
     res
 }
 
+#[cfg(test)]
 mod test {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use regex::Regex;
     #[test]
     fn test_split_mark_only() {
@@ -144,10 +304,216 @@ xyzw
                 lang: Some("start".to_string())
             })
         );
-        //assert_eq!(res.get(2), Some(&Mark::Content{text:"\nhjklm\n".to_string(), lang: Some("start".to_string())}));
-        //assert_eq!(res.get(3), Some(&Mark::Marker{text:"```".to_string(), lang: None}));
-        //assert_eq!(res.get(4), Some(&Mark::Content{text:"\nxyzw\n".to_string(), lang: None}));
+
+        // the ordered markers cannot tell the closing fence, the fences can
+        let res = marks(&input);
+        assert_eq!(res.len(), 5);
+        assert_eq!(
+            res.get(2),
+            Some(&Mark::Content {
+                text: "hjklm\n".to_string(),
+                lang: Some("start".to_string())
+            })
+        );
+        assert_eq!(
+            res.get(3),
+            Some(&Mark::Marker {
+                text: "```".to_string(),
+                lang: None
+            })
+        );
+        assert_eq!(
+            res.get(4),
+            Some(&Mark::Content {
+                text: "\nxyzw\n".to_string(),
+                lang: None
+            })
+        );
+    }
+    #[test]
+    fn test_extract_code() {
+        let input = "Here it is:\n```rust\nfn main() {}\n```\nbye\n";
+        assert_eq!(
+            extract_code(input),
+            Some(("rust".to_string(), "fn main() {}\n".to_string()))
+        );
+        assert_eq!(extract_code("no code"), None);
+    }
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let input = "```sh\necho a\n```\n```\nplain\n```\n```json {}\n[1]\n```";
+        assert_eq!(
+            extract_code_blocks(input),
+            vec![
+                ("sh".to_string(), "echo a\n".to_string()),
+                ("json".to_string(), "[1]\n".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_code_blocks() {
+        let input =
+            "text\n````md info\n```rust\nfn main() {}\n```\n````\n  ~~~\n  a\n b\n~~~~\n```\nopen";
+        let blocks = code_blocks(input);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].lang.as_deref(), Some("md"));
+        assert_eq!(blocks[0].info, "md info");
+        assert_eq!(blocks[0].code, "```rust\nfn main() {}\n```\n");
+        assert_eq!(
+            &input[blocks[0].range.clone()],
+            "````md info\n```rust\nfn main() {}\n```\n````"
+        );
+        assert_eq!(blocks[0].lines, (2, 6));
+        assert_eq!(blocks[1].lang, None);
+        assert_eq!(blocks[1].code, "a\nb\n");
+        assert_eq!(blocks[1].lines, (7, 10));
+        // not closed: runs to the end
+        assert_eq!(blocks[2].code, "open");
+        assert_eq!(blocks[2].lines, (11, 12));
+        assert_eq!(blocks[2].range.end, input.len());
+        // backticks in the info string of a backtick fence
+        assert_eq!(code_blocks("``` a`b\nx\n```\n").len(), 1);
+        assert_eq!(code_blocks("``` a`b\nx\n```\n")[0].lines, (3, 3));
+        assert_eq!(code_blocks("    ```\nindented\n    ```"), vec![]);
+    }
+
+    #[test]
+    fn test_marks() {
+        // with a second block after the text
+        let input = "asdf\n```start\nhjklm\n```\nxyzw\n```sh\nls\n```\n";
+        let content = |text: &str, lang: Option<&str>| Mark::Content {
+            text: text.to_string(),
+            lang: lang.map(str::to_string),
+        };
+        let marker = |text: &str, lang: Option<&str>| Mark::Marker {
+            text: text.to_string(),
+            lang: lang.map(str::to_string),
+        };
+        assert_eq!(
+            marks(input),
+            vec![
+                content("asdf\n", None),
+                marker("```start\n", Some("start")),
+                content("hjklm\n", Some("start")),
+                marker("```", None),
+                content("\nxyzw\n", None),
+                marker("```sh\n", Some("sh")),
+                content("ls\n", Some("sh")),
+                marker("```", None),
+                content("\n", None),
+            ]
+        );
+    }
+
+    /// Markdown made of random paragraphs and fenced blocks, whose code has
+    /// lines looking like fences which do not close the block.
+    fn arbitrary_markdown(rng: &mut StdRng) -> (String, Vec<CodeBlock>) {
+        let mut lines: Vec<String> = vec![];
+        let mut blocks = vec![];
+        let segments = rng.gen_range(0..6);
+        for i in 0..segments {
+            if rng.gen_bool(0.5) {
+                let texts = [
+                    "some text",
+                    "a ```b``` c",
+                    "    ```indented",
+                    "~~ two",
+                    "``` a`b",
+                    "",
+                ];
+                for _ in 0..rng.gen_range(1..3) {
+                    lines.push(texts[rng.gen_range(0..texts.len())].to_string());
+                }
+                continue;
+            }
+            let c = if rng.gen_bool(0.5) { '`' } else { '~' };
+            let len = rng.gen_range(3..6);
+            let indent = rng.gen_range(0..4);
+            let fence = c.to_string().repeat(len);
+            let other = if c == '`' { "~~~~~~" } else { "``````" };
+            let lang = ["", "rust", "json", "c++"][rng.gen_range(0..4)];
+            let info = match (lang, rng.gen_bool(0.3)) {
+                ("", _) | (_, false) => lang.to_string(),
+                (_, true) => format!("{} title=x", lang),
+            };
+            let start = lines.len() + 1;
+            lines.push(format!("{}{}{}", " ".repeat(indent), fence, info));
+            let mut code = String::new();
+            for _ in 0..rng.gen_range(0..4) {
+                let body = [
+                    "code".to_string(),
+                    String::new(),
+                    fence[1..].to_string(),
+                    other.to_string(),
+                    format!("{} info", fence),
+                    format!("    {}", fence),
+                ];
+                let line = format!(
+                    "{}{}",
+                    " ".repeat(rng.gen_range(0..3)),
+                    body[rng.gen_range(0..body.len())]
+                );
+                let spaces = line.len() - line.trim_start_matches(' ').len();
+                code.push_str(&line[spaces.min(indent)..]);
+                code.push('\n');
+                lines.push(line);
+            }
+            // only the last block may be left open
+            let closed = i + 1 < segments || rng.gen_bool(0.7);
+            if closed {
+                lines.push(format!(
+                    "{}{}{}",
+                    " ".repeat(rng.gen_range(0..4)),
+                    c.to_string().repeat(len + rng.gen_range(0..2)),
+                    if rng.gen_bool(0.3) { "  " } else { "" }
+                ));
+            }
+            blocks.push(CodeBlock {
+                lang: (!lang.is_empty()).then(|| lang.to_string()),
+                info,
+                code,
+                range: 0..0,
+                code_range: 0..0,
+                lines: (start, lines.len()),
+            });
+        }
+        (lines.join("\n") + "\n", blocks)
+    }
+
+    #[test]
+    fn test_code_blocks_arbitrary() {
+        for seed in 0..1000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (source, expected) = arbitrary_markdown(&mut rng);
+            let blocks = code_blocks(&source);
+            let found: Vec<_> = blocks
+                .iter()
+                .map(|b| (&b.lang, &b.info, &b.code, b.lines))
+                .collect();
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|b| (&b.lang, &b.info, &b.code, b.lines))
+                .collect();
+            assert_eq!(found, expected, "seed {}:\n{}", seed, source);
+            let mut end = 0;
+            for block in &blocks {
+                assert!(end <= block.range.start, "seed {}", seed);
+                assert!(block.range.start <= block.code_range.start);
+                assert!(block.code_range.end <= block.range.end);
+                end = block.range.end;
+            }
+            let joined: String = marks(&source)
+                .into_iter()
+                .map(|mark| match mark {
+                    Mark::Marker { text, .. } | Mark::Content { text, .. } => text,
+                })
+                .collect();
+            assert_eq!(joined, source, "seed {}", seed);
+        }
     }
+
     #[test]
     fn test_regex() {
         let rex_str = r#"^([a-zA-Z]+)[0-9]+"#;
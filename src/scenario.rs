@@ -1,8 +1,13 @@
-use log::debug;
-use serde::Deserialize;
+use crate::compile::Toolchain;
+use crate::execute::Sandbox;
+use crate::schema;
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use thiserror::Error;
 
 type Tag = String;
 type Name = String;
@@ -11,11 +16,106 @@ type Name = String;
 pub struct Input {
     pub prefix: Option<String>,
     pub text: String,
+    // run on the program built from the answer
+    #[serde(default)]
+    pub tests: Vec<TestCase>,
+    // yaml list of more test cases
+    pub test_file: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TestCase {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub stdin: String,
+    // expected stdout. Only the exit code is checked if omitted.
+    pub expected: Option<String>,
 }
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct Prompt {
     pub instruction: String,
     pub inputs: HashMap<Tag, Input>,
+    // config key of the service used by this assistant. --config-key is used if omitted.
+    pub service: Option<String>,
+    // functions the assistant may call. They are executed locally.
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tool {
+    pub name: String,
+    pub description: Option<String>,
+    // JSON schema of the arguments
+    pub parameters: Option<serde_json::Value>,
+    pub handler: Handler,
+}
+
+/// How a tool call is executed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Handler {
+    /// `sh -c command` with the arguments (json) on stdin. stdout is the output.
+    /// It runs in `sandbox` like a built program: in a temporary directory,
+    /// with only the listed environment variables, and killed on timeout.
+    Shell {
+        command: String,
+        #[serde(default)]
+        sandbox: Sandbox,
+    },
+    /// Compiles the `source` argument with the toolchain of `lang`.
+    Compile {
+        lang: String,
+        // taken from the toolchain registry when omitted
+        #[serde(default)]
+        toolchain: Option<Toolchain>,
+    },
+}
+
+/// The item a transition goes to.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Target {
+    pub name: Name,
+    pub tag: Tag,
+}
+
+/// A condition on the answer of the item a `Branch` leaves.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum Guard {
+    /// The regex matches the last answer.
+    Matches(String),
+    /// The value at the JSON pointer of the answer, or of its code block, equals `equals`.
+    Json {
+        pointer: String,
+        equals: serde_json::Value,
+    },
+    /// The last build of the answer succeeded (true) or failed (false).
+    Built(bool),
+    /// The answer was built and all of its tests passed (true) or not (false).
+    Passed(bool),
+    /// The item has been answered at least this many times.
+    Iterations(usize),
+    // a struct variant, as serde_yaml cannot read an enum right inside another
+    Not {
+        guard: Box<Guard>,
+    },
+}
+
+impl Guard {
+    pub fn needs_build(&self) -> bool {
+        match self {
+            Guard::Built(_) | Guard::Passed(_) => true,
+            Guard::Not { guard } => guard.needs_build(),
+            _ => false,
+        }
+    }
+}
+
+/// An edge of a `Branch`. The workflow stops if there is no target.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Edge {
+    pub when: Guard,
+    #[serde(default)]
+    pub to: Option<Target>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
@@ -31,6 +131,78 @@ pub enum StateTrans {
         name: Name,
         tag: Tag,
     },
+    /// Compiles the code in the answer. A failed build asks the same item again,
+    /// with the errors in `{{compile_errors}}`, at most `retries` times.
+    /// A successful build goes on to `(name, tag)` like `Next`.
+    Repair {
+        retries: u8,
+        name: Name,
+        tag: Tag,
+    },
+    /// Builds the code in the answer and runs the tests of the input on it.
+    /// Goes to `passed` when all of them pass and to `failed` when the build
+    /// or a test fails. The workflow stops where no target is given.
+    Test {
+        passed: Option<Target>,
+        failed: Option<Target>,
+    },
+    /// Goes to the target of the first edge whose guard holds, or to `default`.
+    /// The answer is built first if a guard is on the build or the tests.
+    Branch {
+        edges: Vec<Edge>,
+        #[serde(default)]
+        default: Option<Target>,
+    },
+    /// Asks every item of `to` at the same time, each with its request rendered
+    /// from the conversation as it is before any of them is asked, and goes to
+    /// `join` once all of them answered. The `next` of the items of `to` is not followed.
+    FanOut {
+        to: Vec<Target>,
+        join: Target,
+    },
+}
+
+impl StateTrans {
+    /// Items this transition may go to.
+    pub fn targets(&self) -> Vec<(Name, Tag)> {
+        let pair = |t: &Target| (t.name.clone(), t.tag.clone());
+        match self {
+            StateTrans::Stop => vec![],
+            StateTrans::Next { name, tag, .. }
+            | StateTrans::Wait { name, tag }
+            | StateTrans::Repair { name, tag, .. } => vec![(name.clone(), tag.clone())],
+            StateTrans::Test { passed, failed } => {
+                passed.iter().chain(failed.iter()).map(pair).collect()
+            }
+            StateTrans::Branch { edges, default } => edges
+                .iter()
+                .filter_map(|e| e.to.as_ref())
+                .chain(default.iter())
+                .map(pair)
+                .collect(),
+            StateTrans::FanOut { to, join } => to.iter().chain([join]).map(pair).collect(),
+        }
+    }
+
+    /// Whether the answer is built and tested before going on.
+    pub fn needs_build(&self) -> bool {
+        match self {
+            StateTrans::Repair { .. } | StateTrans::Test { .. } => true,
+            StateTrans::Branch { edges, .. } => edges.iter().any(|e| e.when.needs_build()),
+            _ => false,
+        }
+    }
+}
+
+/// The JSON Schema the answer of an item follows. The JSON is taken from the
+/// ```json block of the answer, or the answer itself. An answer which does not
+/// follow the schema is asked again with what is wrong, at most `retries` times.
+/// A keyword `schema::validate` does not understand is an error of `validate`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct JsonAnswer {
+    pub schema: serde_json::Value,
+    #[serde(default)]
+    pub retries: u8,
 }
 
 pub trait Renderer<S, T> {
@@ -50,6 +222,9 @@ where
     pub next: StateTrans,
     pub request: Box<I>,
     pub response: Box<O>,
+    pub json: Option<JsonAnswer>,
+    // regexes splitting the answers into blocks, in place of --markers
+    pub markers: Option<Vec<String>>,
 }
 
 /* As #[derive(Deserialize)] requires S and T to be Deserializable,
@@ -70,12 +245,18 @@ where
             next: StateTrans,
             request: I,
             response: O,
+            #[serde(default)]
+            json: Option<JsonAnswer>,
+            #[serde(default)]
+            markers: Option<Vec<String>>,
         }
         let Inner {
             start,
             next,
             request,
             response,
+            json,
+            markers,
         } = Inner::deserialize(deserializer)?;
         Ok(Item {
             _s: PhantomData,
@@ -84,6 +265,8 @@ where
             next,
             request,
             response,
+            json,
+            markers,
         })
     }
 }
@@ -101,6 +284,8 @@ where
             next: self.next.clone(),
             request: self.request.clone(),
             response: self.response.clone(),
+            json: self.json.clone(),
+            markers: self.markers.clone(),
         }
     }
 }
@@ -128,18 +313,71 @@ where
     res
 }
 
-pub fn parse_scenario<S, T, I, O>(
-    prompts: HashMap<String, Box<Prompt>>,
-    wf: Workflow<S, T, I, O>,
-) -> Option<(HashMap<String, Box<Prompt>>, Workflow<S, T, I, O>)>
+/// A problem found in the prompts and the workflow.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum Diagnostic {
+    #[error("no prompt")]
+    NoPrompt,
+    #[error("prompt input ({0}, {1}) has no workflow item")]
+    MissingItem(Name, Tag),
+    #[error("workflow item ({0}, {1}) has no prompt input")]
+    MissingInput(Name, Tag),
+    #[error("({0}, {1}) goes to ({2}, {3}) which does not exist")]
+    UnknownTarget(Name, Tag, Name, Tag),
+    #[error("({0}, {1}): invalid regex {2}: {3}")]
+    InvalidGuard(Name, Tag, String, String),
+    #[error("({0}, {1}): invalid marker {2}: {3}")]
+    InvalidMarker(Name, Tag, String, String),
+    #[error("no item has start: true")]
+    NoStart,
+    #[error("more than one item has start: true: {0}")]
+    MultipleStarts(String),
+    #[error("({0}, {1}) fans out to {2} more than once, which has a single thread")]
+    SharedFanOut(Name, Tag, Name),
+    #[error("({0}, {1}): JSON schema keyword {2} is not supported, so it would not be checked")]
+    UnsupportedSchema(Name, Tag, String),
+    #[error("({0}, {1}) cannot be reached from the start item")]
+    Unreachable(Name, Tag),
+    #[error("template missing: {0}")]
+    TemplateMissing(String),
+    #[error("{0}: {1}")]
+    TemplateSyntax(String, String),
+}
+
+impl Diagnostic {
+    /// Warnings do not keep the workflow from running.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Diagnostic::Unreachable(..))
+    }
+}
+
+fn sorted<V>(pairs: HashSet<(Name, Tag)>, f: impl Fn(Name, Tag) -> V) -> Vec<V> {
+    let mut pairs: Vec<_> = pairs.into_iter().collect();
+    pairs.sort();
+    pairs.into_iter().map(|(n, t)| f(n, t)).collect()
+}
+
+fn guards(guard: &Guard) -> Vec<&Guard> {
+    match guard {
+        Guard::Not { guard } => guards(guard),
+        otherwise => vec![otherwise],
+    }
+}
+
+/// Everything wrong with the prompts and the workflow, except templates.
+pub fn validate<S, T, I, O>(
+    prompts: &HashMap<String, Box<Prompt>>,
+    wf: &Workflow<S, T, I, O>,
+) -> Vec<Diagnostic>
 where
     S: Debug,
     T: Debug,
     I: Renderer<S, T> + Clone + Debug,
     O: Renderer<S, T> + Clone + Debug,
 {
+    let mut res = vec![];
     if prompts.is_empty() {
-        return None;
+        res.push(Diagnostic::NoPrompt);
     }
 
     let prompt_pairs: HashSet<(String, String)> = prompts
@@ -153,12 +391,135 @@ where
         .map(|(n, hm)| hm.iter().map(|(t, _itm)| (n.clone(), t.clone())))
         .flatten()
         .collect();
-    if prompt_pairs.eq(&wf_pairs) {
-        Some((prompts, wf))
+    debug!("{:?}", prompt_pairs);
+    debug!("{:?}", wf_pairs);
+    let missing_items = prompt_pairs.difference(&wf_pairs).cloned().collect();
+    res.extend(sorted(missing_items, Diagnostic::MissingItem));
+    let missing_inputs = wf_pairs.difference(&prompt_pairs).cloned().collect();
+    res.extend(sorted(missing_inputs, Diagnostic::MissingInput));
+
+    let mut items: Vec<_> = wf
+        .iter()
+        .flat_map(|(n, hm)| hm.iter().map(move |(t, item)| (n, t, item)))
+        .collect();
+    items.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+    for (name, tag, item) in &items {
+        for (to_name, to_tag) in item.next.targets() {
+            if !wf_pairs.contains(&(to_name.clone(), to_tag.clone())) {
+                res.push(Diagnostic::UnknownTarget(
+                    name.to_string(),
+                    tag.to_string(),
+                    to_name,
+                    to_tag,
+                ));
+            }
+        }
+        if let StateTrans::Branch { edges, .. } = &item.next {
+            for guard in edges.iter().flat_map(|e| guards(&e.when)) {
+                if let Guard::Matches(pattern) = guard {
+                    if let Err(e) = Regex::new(pattern) {
+                        res.push(Diagnostic::InvalidGuard(
+                            name.to_string(),
+                            tag.to_string(),
+                            pattern.clone(),
+                            e.to_string(),
+                        ));
+                    }
+                }
+            }
+        }
+        for pattern in item.markers.iter().flatten() {
+            if let Err(e) = Regex::new(pattern) {
+                res.push(Diagnostic::InvalidMarker(
+                    name.to_string(),
+                    tag.to_string(),
+                    pattern.clone(),
+                    e.to_string(),
+                ));
+            }
+        }
+        for keyword in item
+            .json
+            .iter()
+            .flat_map(|j| schema::unsupported(&j.schema))
+        {
+            res.push(Diagnostic::UnsupportedSchema(
+                name.to_string(),
+                tag.to_string(),
+                keyword,
+            ));
+        }
+        if let StateTrans::FanOut { to, .. } = &item.next {
+            let mut names: Vec<&Name> = to.iter().map(|t| &t.name).collect();
+            names.sort();
+            let mut shared: Vec<&Name> = names
+                .windows(2)
+                .filter(|w| w[0] == w[1])
+                .map(|w| w[0])
+                .collect();
+            shared.dedup();
+            for assistant in shared {
+                res.push(Diagnostic::SharedFanOut(
+                    name.to_string(),
+                    tag.to_string(),
+                    assistant.clone(),
+                ));
+            }
+        }
+    }
+
+    let mut starts = find_start_items(wf);
+    starts.sort();
+    match starts.len() {
+        0 => res.push(Diagnostic::NoStart),
+        1 => {
+            let mut reached: HashSet<(Name, Tag)> = HashSet::new();
+            let mut pending = starts.clone();
+            while let Some(pair) = pending.pop() {
+                if reached.insert(pair.clone()) {
+                    if let Some(item) = wf.get(&pair.0).and_then(|hm| hm.get(&pair.1)) {
+                        pending.extend(item.next.targets());
+                    }
+                }
+            }
+            let unreachable = wf_pairs.difference(&reached).cloned().collect();
+            res.extend(sorted(unreachable, Diagnostic::Unreachable));
+        }
+        _ => {
+            let starts: Vec<_> = starts
+                .iter()
+                .map(|(n, t)| format!("({}, {})", n, t))
+                .collect();
+            res.push(Diagnostic::MultipleStarts(starts.join(", ")));
+        }
+    }
+    res
+}
+
+pub type Scenario<S, T, I, O> = (HashMap<String, Box<Prompt>>, Workflow<S, T, I, O>);
+
+/// The prompts and the workflow if `validate` finds no error, otherwise the errors.
+/// Warnings are logged.
+pub fn parse_scenario<S, T, I, O>(
+    prompts: HashMap<String, Box<Prompt>>,
+    wf: Workflow<S, T, I, O>,
+) -> Result<Scenario<S, T, I, O>, Vec<Diagnostic>>
+where
+    S: Debug,
+    T: Debug,
+    I: Renderer<S, T> + Clone + Debug,
+    O: Renderer<S, T> + Clone + Debug,
+{
+    let (errors, warnings): (Vec<_>, Vec<_>) = validate(&prompts, &wf)
+        .into_iter()
+        .partition(|d| d.is_error());
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+    if errors.is_empty() {
+        Ok((prompts, wf))
     } else {
-        debug!("{:?}", prompt_pairs);
-        debug!("{:?}", wf_pairs);
-        None
+        Err(errors)
     }
 }
 
@@ -0,0 +1,307 @@
+use regex::Regex;
+use serde_json::Value;
+
+/// Where `value` does not follow `schema`, one message per problem, each
+/// starting with the JSON pointer of the offending value.
+///
+/// Only the usual keywords of JSON Schema are understood, those of `KEYWORDS`.
+/// Any other keyword is ignored here, so `unsupported` is checked when the
+/// workflow is loaded.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = vec![];
+    check(schema, value, "", &mut errors);
+    errors
+}
+
+const KEYWORDS: [&str; 16] = [
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minimum",
+    "maximum",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "anyOf",
+    "allOf",
+];
+
+// they do not change what is valid
+const ANNOTATIONS: [&str; 7] = [
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+];
+
+/// The JSON pointer of each keyword of `schema` which `validate` does not
+/// understand, such as `$ref`, `oneOf` or `format`.
+pub fn unsupported(schema: &Value) -> Vec<String> {
+    let mut found = vec![];
+    keywords(schema, "", &mut found);
+    found
+}
+
+fn keywords(schema: &Value, at: &str, found: &mut Vec<String>) {
+    let Value::Object(schema) = schema else {
+        return;
+    };
+    for (keyword, value) in schema {
+        let at = format!("{}/{}", at, escape(keyword));
+        match (keyword.as_str(), value) {
+            ("properties", Value::Object(properties)) => {
+                for (field, s) in properties {
+                    keywords(s, &format!("{}/{}", at, escape(field)), found);
+                }
+            }
+            ("anyOf" | "allOf", Value::Array(schemas)) => {
+                for (i, s) in schemas.iter().enumerate() {
+                    keywords(s, &format!("{}/{}", at, i), found);
+                }
+            }
+            // a list of schemas, one per position, is not understood
+            ("items", Value::Array(_)) => found.push(at),
+            ("items" | "additionalProperties", s) => keywords(s, &at, found),
+            (k, _) if KEYWORDS.contains(&k) || ANNOTATIONS.contains(&k) => (),
+            _ => found.push(at),
+        }
+    }
+}
+
+fn escape(field: &str) -> String {
+    field.replace('~', "~0").replace('/', "~1")
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    let actual = type_name(value);
+    actual == expected || (expected == "number" && actual == "integer")
+}
+
+fn check(schema: &Value, value: &Value, at: &str, errors: &mut Vec<String>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => return errors.push(format!("{}: not allowed", pointer(at))),
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+    let mut fail = |message: String| errors.push(format!("{}: {}", pointer(at), message));
+    match schema.get("type") {
+        Some(Value::String(t)) if !has_type(value, t) => {
+            return fail(format!("expected {}, got {}", t, type_name(value)));
+        }
+        Some(Value::Array(types))
+            if !types
+                .iter()
+                .any(|t| t.as_str().is_some_and(|t| has_type(value, t))) =>
+        {
+            let types: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+            return fail(format!(
+                "expected one of {}, got {}",
+                types.join(", "),
+                type_name(value)
+            ));
+        }
+        _ => (),
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            fail(format!(
+                "{} is not one of {}",
+                value,
+                Value::from(allowed.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            fail(format!("{} is not {}", value, expected));
+        }
+    }
+    if let Some(n) = value.as_f64() {
+        if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+            if n < min {
+                fail(format!("{} is less than the minimum {}", value, min));
+            }
+        }
+        if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+            if n > max {
+                fail(format!("{} is greater than the maximum {}", value, max));
+            }
+        }
+    }
+    if let Value::String(s) = value {
+        let len = s.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+            if len < min {
+                fail(format!("shorter than {} characters", min));
+            }
+        }
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+            if len > max {
+                fail(format!("longer than {} characters", max));
+            }
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+            match Regex::new(pattern) {
+                Ok(re) if !re.is_match(s) => fail(format!("does not match {}", pattern)),
+                Ok(_) => (),
+                Err(e) => fail(format!("invalid pattern {}: {}", pattern, e)),
+            }
+        }
+    }
+    if let Value::Array(items) = value {
+        let len = items.len() as u64;
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if len < min {
+                fail(format!("fewer than {} items", min));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if len > max {
+                fail(format!("more than {} items", max));
+            }
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                check(item_schema, item, &format!("{}/{}", at, i), errors);
+            }
+        }
+    }
+    if let Value::Object(fields) = value {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for field in required.iter().filter_map(Value::as_str) {
+                if !fields.contains_key(field) {
+                    errors.push(format!("{}: missing property {}", pointer(at), field));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (field, v) in fields {
+            let at = format!("{}/{}", at, escape(field));
+            match (
+                properties.and_then(|p| p.get(field)),
+                schema.get("additionalProperties"),
+            ) {
+                (Some(s), _) => check(s, v, &at, errors),
+                (None, Some(Value::Bool(false))) => {
+                    errors.push(format!("{}: property not allowed", pointer(&at)))
+                }
+                (None, Some(s)) => check(s, v, &at, errors),
+                (None, None) => (),
+            }
+        }
+    }
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+        for s in all {
+            check(s, value, at, errors);
+        }
+    }
+    if let Some(Value::Array(any)) = schema.get("anyOf") {
+        if !any.iter().any(|s| validate(s, value).is_empty()) {
+            errors.push(format!("{}: matches none of anyOf", pointer(at)));
+        }
+    }
+}
+
+fn pointer(at: &str) -> &str {
+    if at.is_empty() {
+        "/"
+    } else {
+        at
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "required": ["verdict", "score"],
+            "additionalProperties": false,
+            "properties": {
+                "verdict": { "enum": ["APPROVED", "REJECTED"] },
+                "score": { "type": "integer", "minimum": 0, "maximum": 10 },
+                "notes": { "type": "array", "items": { "type": "string" }, "maxItems": 2 },
+            },
+        });
+        let ok = json!({ "verdict": "APPROVED", "score": 7, "notes": ["fine"] });
+        assert_eq!(validate(&schema, &ok), Vec::<String>::new());
+
+        let bad = json!({ "verdict": "MAYBE", "score": 11, "notes": ["a", 1, "c"], "x": 0 });
+        assert_eq!(
+            validate(&schema, &bad),
+            vec![
+                "/notes: more than 2 items",
+                "/notes/1: expected string, got integer",
+                "/score: 11 is greater than the maximum 10",
+                "/verdict: \"MAYBE\" is not one of [\"APPROVED\",\"REJECTED\"]",
+                "/x: property not allowed",
+            ]
+        );
+        assert_eq!(
+            validate(&schema, &json!({ "score": 1.5 })),
+            vec![
+                "/: missing property verdict",
+                "/score: expected integer, got number",
+            ]
+        );
+        assert_eq!(
+            validate(&schema, &json!([])),
+            vec!["/: expected object, got array"]
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "description": "a review",
+            "properties": {
+                "score": { "type": "integer", "exclusiveMinimum": 0 },
+                "a/b": { "format": "email" },
+                "tags": { "items": { "oneOf": [] } },
+                "pair": { "items": [{ "type": "string" }] },
+            },
+            "anyOf": [{ "not": {} }],
+            "minProperties": 1,
+        });
+        assert_eq!(
+            unsupported(&schema),
+            vec![
+                "/anyOf/0/not",
+                "/minProperties",
+                "/properties/a~1b/format",
+                "/properties/pair/items",
+                "/properties/score/exclusiveMinimum",
+                "/properties/tags/items/oneOf",
+            ]
+        );
+        assert_eq!(unsupported(&json!(true)), Vec::<String>::new());
+    }
+}
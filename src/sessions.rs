@@ -0,0 +1,175 @@
+use crate::openai_api::{AssistantName, Backends, Session};
+use crate::{AssistantError, Tag, Talk};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// File in the output directory holding the sessions set up by `connect`.
+pub const SESSIONS_FILE: &str = "sessions.yaml";
+
+fn sessions_path(output_dir: &str) -> PathBuf {
+    PathBuf::from(output_dir).join(SESSIONS_FILE)
+}
+
+/// Sessions saved in `output_dir`, oldest first. Nothing is saved yet if the file is missing.
+pub fn read_sessions(output_dir: &str) -> Result<Vec<Session>, AssistantError> {
+    let path = sessions_path(output_dir);
+    if !path.exists() {
+        warn!("{:?} not found, no session to resume", &path);
+        return Ok(vec![]);
+    }
+    let content = fs::read_to_string(&path)?;
+    serde_yaml::from_str(&content)
+        .map_err(|e| AssistantError::FileOpenFailed(format!("{}: {}", path.display(), e)))
+}
+
+fn store_sessions(output_dir: &str, sessions: &[Session]) -> Result<(), AssistantError> {
+    fs::create_dir_all(output_dir)?;
+    let s = serde_yaml::to_string(sessions)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(sessions_path(output_dir), s)?;
+    Ok(())
+}
+
+// what a session holds on its service
+fn session_key(session: &Session) -> (&str, &str, &str) {
+    (&session.service, &session.assistant_id, &session.thread_id)
+}
+
+/// Adds `sessions` to those saved in `output_dir`, so that cleanup also finds
+/// the ones of earlier launches. Only a saved session with the same assistant
+/// and thread on the same service, i.e. one which was resumed, is replaced.
+pub fn write_sessions(
+    output_dir: &str,
+    sessions: &HashMap<AssistantName, Session>,
+) -> Result<(), AssistantError> {
+    let mut saved = read_sessions(output_dir)?;
+    saved.retain(|old| {
+        sessions
+            .values()
+            .all(|new| session_key(old) != session_key(new))
+    });
+    let mut added: Vec<&Session> = sessions.values().collect();
+    added.sort_by(|a, b| a.name.cmp(&b.name));
+    saved.extend(added.into_iter().cloned());
+    store_sessions(output_dir, &saved)
+}
+
+/// Counters of an item which go down as the workflow runs.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Counters {
+    /// `auto` of a `Next`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto: Option<u8>,
+    /// `retries` of a `Repair`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u8>,
+    /// `retries` of the JSON schema
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_retries: Option<u8>,
+}
+
+/// A workflow as far as it went, to go on with it later with `--session`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SavedSession {
+    pub conversations: Vec<Talk>,
+    /// The item being worked on. None once the workflow stopped.
+    pub current: Option<(AssistantName, Tag)>,
+    pub counters: BTreeMap<AssistantName, BTreeMap<Tag, Counters>>,
+    /// Contents of the Input and Result panes
+    #[serde(default)]
+    pub input: String,
+    #[serde(default)]
+    pub result: String,
+    /// Assistants and threads, which are reattached.
+    pub sessions: HashMap<AssistantName, Session>,
+}
+
+pub fn read_session(path: &Path) -> Result<SavedSession, AssistantError> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| AssistantError::FileOpenFailed(format!("{}: {}", path.display(), e)))?;
+    serde_yaml::from_str(&content)
+        .map_err(|e| AssistantError::FileOpenFailed(format!("{}: {}", path.display(), e)))
+}
+
+pub fn write_session(path: &Path, session: &SavedSession) -> Result<(), AssistantError> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let s = serde_yaml::to_string(session)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(path, s)?;
+    Ok(())
+}
+
+/// Deletes the sessions saved in `output_dir` on their services. Sessions which
+/// could not be deleted are kept in the file so that cleanup can be retried.
+pub async fn cleanup(backends: &Backends, output_dir: &str) -> Result<(), AssistantError> {
+    let mut left = vec![];
+    let mut first_error = None;
+    for session in read_sessions(output_dir)? {
+        let name = session.name.clone();
+        let deleted = match backends.get(&session.service) {
+            Some(backend) => backend
+                .delete_session(&session)
+                .await
+                .map_err(AssistantError::from),
+            None => Err(AssistantError::InvalidConfigKey(session.service.clone())),
+        };
+        match deleted {
+            Ok(()) => info!("Deleted session of {}", &name),
+            Err(e) => {
+                error!("Failed to delete session of {}: {}", &name, &e);
+                first_error.get_or_insert(e);
+                left.push(session);
+            }
+        }
+    }
+    if left.is_empty() {
+        let path = sessions_path(output_dir);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    } else {
+        store_sessions(output_dir, &left)?;
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_dir = dir.path().to_str().unwrap();
+        let launch = |id: &str| {
+            let session = Session {
+                name: "king".to_string(),
+                service: "openai".to_string(),
+                assistant_id: format!("asst_{}", id),
+                thread_id: format!("thread_{}", id),
+                ..Session::default()
+            };
+            HashMap::from([("king".to_string(), session)])
+        };
+        let ids = || -> Vec<String> {
+            read_sessions(output_dir)
+                .unwrap()
+                .into_iter()
+                .map(|s| s.assistant_id)
+                .collect()
+        };
+        // two fresh launches of the same assistant on the same service
+        write_sessions(output_dir, &launch("1")).unwrap();
+        write_sessions(output_dir, &launch("2")).unwrap();
+        assert_eq!(ids(), vec!["asst_1", "asst_2"]);
+        // the first one resumed
+        write_sessions(output_dir, &launch("1")).unwrap();
+        assert_eq!(ids(), vec!["asst_2", "asst_1"]);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::compile::{Build, Toolchain};
+use crate::execute::Execution;
+use crate::scenario::{Prompt, TestCase};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// A test case run on the program of an answer.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TestResult {
+    pub name: String,
+    pub stdin: String,
+    pub expected: Option<String>,
+    pub passed: bool,
+    pub execution: Execution,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    /// e.g. "2 passed, 1 failed"
+    pub fn summary(&self) -> String {
+        format!("{} passed, {} failed", self.passed(), self.failed())
+    }
+
+    /// What each failed case was given, expected and got.
+    pub fn failures(&self) -> String {
+        self.results
+            .iter()
+            .filter(|r| !r.passed)
+            .map(|r| {
+                let expected = r
+                    .expected
+                    .as_ref()
+                    .map(|e| format!("expected:\n{}\n", e))
+                    .unwrap_or_default();
+                format!(
+                    "--- {}\nstdin:\n{}\n{}got:\n{}",
+                    &r.name,
+                    &r.stdin,
+                    expected,
+                    r.execution.to_text()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+// trailing spaces and newlines do not count
+fn normalize(text: &str) -> String {
+    text.lines()
+        .map(|l| l.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string()
+}
+
+/// Runs each case on the program of `build`. A case passes when the program
+/// exits with 0 and prints what is expected, if anything is.
+pub async fn run_tests(
+    toolchain: &Toolchain,
+    build: &Build,
+    cases: &[TestCase],
+) -> Result<TestReport, io::Error> {
+    let mut results = vec![];
+    for (i, case) in cases.iter().enumerate() {
+        let execution = toolchain.run(build, &case.stdin).await?;
+        let passed = execution.success()
+            && case
+                .expected
+                .as_ref()
+                .is_none_or(|e| normalize(e) == normalize(&execution.stdout));
+        results.push(TestResult {
+            name: case.name.clone().unwrap_or_else(|| format!("#{}", i + 1)),
+            stdin: case.stdin.clone(),
+            expected: case.expected.clone(),
+            passed,
+            execution,
+        });
+    }
+    Ok(TestReport { results })
+}
+
+/// Adds the cases of each `test_file` to the tests of its input. A relative
+/// `test_file` is in `dir`, the directory of the prompt file.
+pub fn load_test_files(
+    prompts: &mut HashMap<String, Box<Prompt>>,
+    dir: &Path,
+) -> Result<(), io::Error> {
+    for input in prompts.values_mut().flat_map(|p| p.inputs.values_mut()) {
+        if let Some(file) = &input.test_file {
+            let path = dir.join(file);
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            let cases: Vec<TestCase> = serde_yaml::from_str(&content).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?;
+            input.tests.extend(cases);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_tests() {
+        let shell = Toolchain {
+            extension: "sh".to_string(),
+            compile: None,
+            run: vec!["sh".to_string(), "{source}".to_string()],
+            timeout_secs: 10,
+            sandbox: Default::default(),
+        };
+        let dir = std::env::temp_dir().join(format!("assistant-testing-{}", std::process::id()));
+        let case = |stdin: &str, expected: Option<&str>| TestCase {
+            name: None,
+            stdin: stdin.to_string(),
+            expected: expected.map(|e| e.to_string()),
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let build = rt.block_on(shell.build(&dir, "read x; echo $x$x")).unwrap();
+        let cases = vec![
+            case("ab\n", Some("abab  \n\n")),
+            case("ab\n", Some("ab")),
+            case("", None),
+        ];
+        let report = rt.block_on(run_tests(&shell, &build, &cases)).unwrap();
+        assert_eq!(
+            report.results.iter().map(|r| r.passed).collect::<Vec<_>>(),
+            vec![true, false, true]
+        );
+        assert_eq!(report.summary(), "2 passed, 1 failed");
+        assert!(report
+            .failures()
+            .starts_with("--- #2\nstdin:\nab\n\nexpected:\nab\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_test_files() {
+        let dir = std::env::temp_dir().join(format!("assistant-tests-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cases.yaml"), "- stdin: a\n  expected: aa\n").unwrap();
+        let mut prompts: HashMap<String, Box<Prompt>> = serde_yaml::from_str(
+            "coder:\n  instruction: i\n  inputs:\n    c1:\n      text: t\n      test_file: cases.yaml\n",
+        )
+        .unwrap();
+        // not where the process runs
+        load_test_files(&mut prompts, &dir).unwrap();
+        let tests = &prompts["coder"].inputs["c1"].tests;
+        assert_eq!(tests.len(), 1);
+        assert_eq!(tests[0].expected.as_deref(), Some("aa"));
+
+        prompts
+            .get_mut("coder")
+            .unwrap()
+            .inputs
+            .get_mut("c1")
+            .unwrap()
+            .test_file = Some("missing.yaml".to_string());
+        let e = load_test_files(&mut prompts, &dir).unwrap_err();
+        assert!(e.to_string().contains("missing.yaml"), "{}", e);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
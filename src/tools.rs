@@ -0,0 +1,133 @@
+use crate::compile::Toolchains;
+use crate::execute::execute;
+use crate::scenario::{Handler, Prompt, Tool};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+
+/// A tool call requested by an assistant and the output sent back to it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub tool: String,
+    pub arguments: String,
+    pub output: String,
+}
+
+/// JSON schema of the arguments of `tool`. `Compile` takes the source when omitted.
+pub fn schema(tool: &Tool) -> Option<Value> {
+    match (&tool.parameters, &tool.handler) {
+        (Some(parameters), _) => Some(parameters.clone()),
+        (None, Handler::Compile { .. }) => Some(json!({
+            "type": "object",
+            "properties": { "source": { "type": "string" } },
+            "required": ["source"],
+        })),
+        (None, Handler::Shell { .. }) => None,
+    }
+}
+
+/// Fills the toolchain of `Compile` handlers which do not define their own.
+pub fn attach_toolchains(prompts: &mut HashMap<String, Box<Prompt>>, toolchains: &Toolchains) {
+    for tool in prompts.values_mut().flat_map(|p| p.tools.iter_mut()) {
+        if let Handler::Compile {
+            lang,
+            toolchain: toolchain @ None,
+        } = &mut tool.handler
+        {
+            *toolchain = toolchains.get(lang).cloned();
+        }
+    }
+}
+
+/// Executes `tool` with `arguments` (json). Failures are given back to the
+/// assistant as the output so that it can try again.
+pub async fn call_tool(tools: &[Tool], tool: &str, arguments: &str) -> ToolCall {
+    let output = match tools.iter().find(|t| t.name == tool) {
+        Some(t) => run_handler(&t.handler, arguments)
+            .await
+            .unwrap_or_else(|e| format!("error: {}", e)),
+        None => format!("error: unknown tool {}", tool),
+    };
+    info!("--- Tool {}({}): {}", tool, arguments, &output);
+    ToolCall {
+        tool: tool.to_string(),
+        arguments: arguments.to_string(),
+        output,
+    }
+}
+
+async fn run_handler(handler: &Handler, arguments: &str) -> Result<String, io::Error> {
+    match handler {
+        Handler::Shell { command, sandbox } => {
+            let program = ["sh".to_string(), "-c".to_string(), command.clone()];
+            let execution = execute(&program, arguments, sandbox).await?;
+            if execution.success() {
+                Ok(execution.stdout)
+            } else {
+                Ok(execution.to_text())
+            }
+        }
+        Handler::Compile { lang, toolchain } => {
+            #[derive(Deserialize)]
+            struct Args {
+                source: String,
+            }
+            let toolchain = toolchain.as_ref().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no toolchain for {}", lang),
+                )
+            })?;
+            let args: Args = serde_json::from_str(arguments)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // one for each call, as calls run at the same time in a fan-out
+            let dir = tempfile::Builder::new()
+                .prefix("assistant-tool")
+                .tempdir()?;
+            let build = toolchain.build(dir.path(), &args.source).await?;
+            let result = if build.success { "succeeded" } else { "failed" };
+            Ok(format!("compile {}\n{}", result, build.output))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::execute::Sandbox;
+
+    #[test]
+    fn test_call_tool() {
+        let tools = vec![Tool {
+            name: "echo".to_string(),
+            description: None,
+            parameters: None,
+            handler: Handler::Shell {
+                command: "cat".to_string(),
+                sandbox: Sandbox::default(),
+            },
+        }];
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let call = rt.block_on(call_tool(&tools, "echo", r#"{"a": 1}"#));
+        assert_eq!(call.output, r#"{"a": 1}"#);
+        let call = rt.block_on(call_tool(&tools, "missing", "{}"));
+        assert_eq!(call.output, "error: unknown tool missing");
+
+        let hanging = vec![Tool {
+            name: "hang".to_string(),
+            description: None,
+            parameters: None,
+            handler: Handler::Shell {
+                command: "sleep 5".to_string(),
+                sandbox: Sandbox {
+                    timeout_secs: 0,
+                    ..Sandbox::default()
+                },
+            },
+        }];
+        let call = rt.block_on(call_tool(&hanging, "hang", "{}"));
+        assert!(call.output.ends_with("--- timed out\n"), "{}", call.output);
+    }
+}
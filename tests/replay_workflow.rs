@@ -0,0 +1,431 @@
+use serde_yaml::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+fn output_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("assistant-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn run(output_dir: &Path, extra: &[&str]) -> Output {
+    run_with_key("replay", output_dir, extra)
+}
+
+fn run_with_key(key: &str, output_dir: &Path, extra: &[&str]) -> Output {
+    command("replay", key, output_dir, extra, &["run"])
+}
+
+fn command(
+    fixture: &str,
+    key: &str,
+    output_dir: &Path,
+    extra: &[&str],
+    subcommand: &[&str],
+) -> Output {
+    command_with_workflow(fixture, "workflow.yaml", key, output_dir, extra, subcommand)
+}
+
+fn command_with_workflow(
+    fixture: &str,
+    workflow: &str,
+    key: &str,
+    output_dir: &Path,
+    extra: &[&str],
+    subcommand: &[&str],
+) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_assistant"))
+        .current_dir(Path::new(FIXTURES).join(fixture))
+        // warnings are checked by some tests
+        .env("RUST_LOG", "warn")
+        .args(["--config-file", "service.yaml", "--config-key", key])
+        .args(["--prompt-file", "prompt.yaml"])
+        .args(["--workflow-file", workflow])
+        .args(["--output-dir", output_dir.to_str().unwrap()])
+        .args(extra)
+        .args(subcommand)
+        .output()
+        .unwrap()
+}
+
+fn answers(conversation: &Path) -> Vec<String> {
+    let content = fs::read_to_string(conversation).unwrap();
+    let talks: Vec<Value> = serde_yaml::from_str(&content).unwrap();
+    talks
+        .iter()
+        .filter_map(|t| match t {
+            Value::Tagged(tagged) if tagged.tag == "FromAi" => {
+                let message = &tagged.value["message"];
+                match message {
+                    Value::Tagged(m) => m.value.as_str().map(|s| s.to_string()),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_replay_workflow() {
+    let out = output_dir("replay");
+    let output = run(&out, &[]);
+    assert!(output.status.success(), "{:?}", output);
+
+    assert_eq!(
+        answers(&out.join("conversation.yaml")),
+        vec!["answer from king".to_string(), "APPROVED".to_string()]
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("APPROVED"));
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_record_then_replay() {
+    let out = output_dir("record");
+    fs::create_dir_all(&out).unwrap();
+    let recorded = out.join("recorded.yaml");
+    let output = run(&out, &["--record", recorded.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        answers(&recorded),
+        vec!["answer from king".to_string(), "APPROVED".to_string()]
+    );
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_invalid_config_key() {
+    let out = output_dir("invalid-key");
+    let output = run_with_key("missing", &out, &[]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("invalid config key: missing"));
+}
+
+#[test]
+fn test_resume_and_cleanup() {
+    let out = output_dir("resume");
+    let output = run(&out, &[]);
+    assert!(output.status.success(), "{:?}", output);
+    let sessions = out.join("sessions.yaml");
+    let saved = |path: &Path| -> Vec<(String, String)> {
+        let saved: Vec<Value> = serde_yaml::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        saved
+            .iter()
+            .map(|s| {
+                let field = |k: &str| s[k].as_str().unwrap().to_string();
+                (field("name"), field("service"))
+            })
+            .collect()
+    };
+    assert_eq!(
+        saved(&sessions),
+        vec![
+            ("king".to_string(), "replay".to_string()),
+            ("queen".to_string(), "replay".to_string()),
+        ]
+    );
+
+    let output = run(&out, &["--resume"]);
+    assert!(output.status.success(), "{:?}", output);
+    // sessions of earlier launches are kept for cleanup
+    let output = run_with_key("again", &out, &[]);
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(&out, &[]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        saved(&sessions),
+        vec![
+            ("king".to_string(), "again".to_string()),
+            ("queen".to_string(), "again".to_string()),
+            ("king".to_string(), "replay".to_string()),
+            ("queen".to_string(), "replay".to_string()),
+        ]
+    );
+
+    let output = command("replay", "replay", &out, &[], &["cleanup"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!sessions.exists());
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_repair_loop() {
+    let out = output_dir("repair");
+    let extra = ["--toolchain-file", "toolchains.yaml"];
+    let output = command("repair", "replay", &out, &extra, &["run"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let content = fs::read_to_string(out.join("conversation.yaml")).unwrap();
+    let talks: Vec<Value> = serde_yaml::from_str(&content).unwrap();
+    let builds: Vec<bool> = talks
+        .iter()
+        .filter_map(|t| match t {
+            Value::Tagged(tagged) if tagged.tag == "Build" => tagged.value["success"].as_bool(),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(builds, vec![false, true]);
+    assert_eq!(
+        answers(&out.join("conversation.yaml")).last(),
+        Some(&"APPROVED".to_string())
+    );
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_test_cases() {
+    let out = output_dir("testcases");
+    let extra = ["--toolchain-file", "toolchains.yaml"];
+    let output = command("testcases", "replay", &out, &extra, &["run"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let content = fs::read_to_string(out.join("conversation.yaml")).unwrap();
+    let talks: Vec<Value> = serde_yaml::from_str(&content).unwrap();
+    let passed: Vec<Vec<bool>> = talks
+        .iter()
+        .filter_map(|t| match t {
+            Value::Tagged(tagged) if tagged.tag == "Tests" => tagged.value["report"]["results"]
+                .as_sequence()
+                .map(|results| {
+                    results
+                        .iter()
+                        .filter_map(|r| r["passed"].as_bool())
+                        .collect()
+                }),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(passed, vec![vec![false], vec![true, true]]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("tests: 2 passed, 0 failed"), "{}", stdout);
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_validate() {
+    let out = output_dir("validate");
+    let output = command("replay", "replay", &out, &[], &["validate"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let output = command("invalid", "replay", &out, &[], &["validate"]);
+    assert_eq!(output.status.code(), Some(4));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for expected in [
+        "error: prompt input (king, k2) has no workflow item",
+        "error: (king, k1) goes to (queen, q1) which does not exist",
+        "error: no item has start: true",
+        "error: broken.hbs: line 3",
+        "error: template missing: missing.hbs",
+    ] {
+        assert!(stdout.contains(expected), "{}", stdout);
+    }
+
+    // the same errors keep the workflow from running
+    let output = command("invalid", "replay", &out, &[], &["run"]);
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn test_graph() {
+    let out = output_dir("graph");
+    let output = command("repair", "replay", &out, &[], &["graph"]);
+    assert!(output.status.success(), "{:?}", output);
+    let dot = String::from_utf8(output.stdout).unwrap();
+    assert!(dot.starts_with("digraph workflow {"), "{}", dot);
+    assert!(dot.contains("\"coder.c1\" [label=\"(coder, c1)\", peripheries=2];"));
+    assert!(dot.contains("\"coder.c1\" -> \"coder.c1\" [label=\"retry 2\"];"));
+
+    // the path of the saved conversation is highlighted
+    let args = [
+        "graph",
+        "--format",
+        "mermaid",
+        "--conversation",
+        "conversation.yaml",
+    ];
+    let output = command("repair", "replay", &out, &[], &args);
+    assert!(output.status.success(), "{:?}", output);
+    let mermaid = String::from_utf8(output.stdout).unwrap();
+    assert!(mermaid.starts_with("flowchart TD"), "{}", mermaid);
+    assert!(mermaid.contains("  n0 -->|\"built\"| n1"), "{}", mermaid);
+    assert!(mermaid.contains("  class n0,n1 visited"), "{}", mermaid);
+    assert!(
+        mermaid.contains("  linkStyle 0,1 stroke:red"),
+        "{}",
+        mermaid
+    );
+}
+
+#[test]
+fn test_fan_out() {
+    let out = output_dir("fanout");
+    let output = command("fanout", "replay", &out, &[], &["run"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        answers(&out.join("conversation.yaml")),
+        vec!["echo hello", "APPROVED", "REJECTED", "1 of 2 approved"]
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        vec!["echo hello", "APPROVED", "REJECTED", "1 of 2 approved"]
+    );
+
+    let args = ["graph", "--conversation", "conversation.yaml"];
+    let output = command("fanout", "replay", &out, &[], &args);
+    let dot = String::from_utf8(output.stdout).unwrap();
+    assert!(dot.contains("\"tester.t1\" -> \"judge.j1\" [label=\"join\", color=red, penwidth=2];"));
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_fan_out_resumed() {
+    let out = output_dir("fanout-resumed");
+    let session = out.join("session.yaml");
+    let extra = ["--session", session.to_str().unwrap()];
+    // the tester has no recorded answer, so the fan-out fails
+    let output = command("fanout", "partial", &out, &extra, &["run"]);
+    assert!(!output.status.success(), "{:?}", output);
+    let saved: Value = serde_yaml::from_str(&fs::read_to_string(&session).unwrap()).unwrap();
+    assert_eq!(saved["current"][0].as_str(), Some("coder"));
+    // the reviewer answered before the tester failed
+    let content = fs::read_to_string(&session).unwrap();
+    assert!(content.contains("message: !Text APPROVED"), "{}", content);
+
+    // and is done again
+    let output = command("fanout", "replay", &out, &extra, &["run"]);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        vec!["echo hello", "APPROVED", "REJECTED", "1 of 2 approved"]
+    );
+    let saved: Value = serde_yaml::from_str(&fs::read_to_string(&session).unwrap()).unwrap();
+    assert!(saved["current"].is_null());
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_inline_templates() {
+    let out = output_dir("inline");
+    // templates in the workflow, a partial and a path next to the workflow file
+    let workflow = "flow/workflow.yaml";
+    let output = command_with_workflow("inline", workflow, "replay", &out, &[], &["run"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        answers(&out.join("conversation.yaml")),
+        vec!["answer from king", "APPROVED"]
+    );
+
+    // an answer which is not there yet is empty and warned about
+    let workflow = "flow/missing.yaml";
+    let output = command_with_workflow("inline", workflow, "replay", &out, &[], &["run"]);
+    assert!(output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("template (king, k1) request: line 1, column"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("missing variable answers.queen.q1"),
+        "{}",
+        stderr
+    );
+
+    // a name templates are not given is an error
+    let workflow = "flow/misspelt.yaml";
+    let output = command_with_workflow("inline", workflow, "replay", &out, &[], &["run"]);
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("missing variable nope"), "{}", stderr);
+
+    let workflow = "flow/broken.yaml";
+    let output = command_with_workflow("inline", workflow, "replay", &out, &[], &["run"]);
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("error: template (king, k1) request: line 1, column 1"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("footer"), "{}", stderr);
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_json_schema() {
+    let out = output_dir("schema");
+    // 11 is above the maximum, so the answer is asked again with what is wrong
+    let output = command("schema", "replay", &out, &[], &["run"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        answers(&out.join("conversation.yaml")),
+        vec![
+            "```json\n{\"score\": 11}\n```",
+            "{\"score\": 7}",
+            "APPROVED"
+        ]
+    );
+    let content = fs::read_to_string(out.join("conversation.yaml")).unwrap();
+    assert!(content.contains("!ProcessedResponse"), "{}", content);
+
+    // without retries the run stops, and the session is kept where it stopped
+    let session = out.join("session.yaml");
+    let extra = ["--session", session.to_str().unwrap()];
+    let output = command_with_workflow("schema", "strict.yaml", "replay", &out, &extra, &["run"]);
+    assert_eq!(output.status.code(), Some(6));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("- /score: 11 is greater than the maximum 10"),
+        "{}",
+        stderr
+    );
+    let saved: Value = serde_yaml::from_str(&fs::read_to_string(&session).unwrap()).unwrap();
+    assert_eq!(saved["current"][0].as_str(), Some("king"));
+    fs::remove_dir_all(&out).unwrap();
+}
+
+#[test]
+fn test_session() {
+    let out = output_dir("session");
+    let session = out.join("session.yaml");
+    let extra = ["--session", session.to_str().unwrap()];
+    // stopped after the first item
+    let output = command(
+        "replay",
+        "replay",
+        &out,
+        &extra,
+        &["run", "--max-steps", "1"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        answers(&out.join("conversation.yaml")),
+        vec!["answer from king"]
+    );
+    let saved: Value = serde_yaml::from_str(&fs::read_to_string(&session).unwrap()).unwrap();
+    assert_eq!(saved["current"][0].as_str(), Some("queen"));
+    assert_eq!(saved["counters"]["king"]["k1"]["auto"].as_u64(), Some(0));
+    assert_eq!(
+        saved["sessions"]["king"]["thread_id"].as_str(),
+        Some("king")
+    );
+
+    // the next day
+    let output = command("replay", "replay", &out, &extra, &["run"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        answers(&out.join("conversation.yaml")),
+        vec!["answer from king", "APPROVED"]
+    );
+    let saved: Value = serde_yaml::from_str(&fs::read_to_string(&session).unwrap()).unwrap();
+    assert!(saved["current"].is_null());
+    fs::remove_dir_all(&out).unwrap();
+}